@@ -0,0 +1,225 @@
+//! Compares reading a large video through a `PacketReader`, as `do_hash`
+//! does, with scanning it in a memory mapping with a `MmapScanner`, as
+//! `HashFile::new_mapped` does.
+//!
+//! ```text
+//! cargo run --release --example bench_mmap -- [video] [size in GB]
+//! ```
+//!
+//! Unless `video` exists, a synthetic video of `size` GB (10 by default) is
+//! written there first, `bench.ts` in the temporary directory by default.
+//! Each method is timed once for packets alone and once for hashing; when
+//! the video does not fit in the page cache, both read it from disk.
+
+use mtf::{
+    do_hash, hash_packets, psi::crc32, HashOptions, Mmap, MmapScanner, PacketReader, ProgramMap,
+};
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+    time::Instant,
+};
+
+fn main() -> anyhow::Result<()> {
+    let mut args = std::env::args().skip(1);
+    let path = args
+        .next()
+        .map(PathBuf::from)
+        .unwrap_or_else(|| std::env::temp_dir().join("bench.ts"));
+    let gigabytes: f64 = args
+        .next()
+        .map(|size| size.parse())
+        .transpose()?
+        .unwrap_or(10.0);
+
+    if !path.exists() {
+        let start = Instant::now();
+        generate(&path, (gigabytes * 1e9) as u64)?;
+        println!("generated {} in {:.1?}", path.display(), start.elapsed());
+    }
+    let size = std::fs::metadata(&path)?.len() as f64;
+    let report = |name: &str, start: Instant| {
+        let seconds = start.elapsed().as_secs_f64();
+        println!(
+            "{name:<24} {seconds:>8.2} s {:>8.0} MB/s",
+            size / seconds / 1e6
+        );
+    };
+
+    let start = Instant::now();
+    let mut reader = PacketReader::open(&path, None)?;
+    let mut packets = 0u64;
+    while reader.next_packet()?.is_some() {
+        packets += 1;
+    }
+    report("PacketReader packets", start);
+
+    let start = Instant::now();
+    // SAFETY: nothing else writes the benchmark video
+    let map = unsafe { Mmap::open(&path)? };
+    let mut scanner = MmapScanner::new(&map, None)?;
+    let mut mapped_packets = 0u64;
+    while scanner.next_raw_packet().is_some() {
+        mapped_packets += 1;
+    }
+    report("MmapScanner packets", start);
+    assert_eq!(packets, mapped_packets);
+
+    let start = Instant::now();
+    let segments = do_hash(&path, HashOptions::default())?;
+    report("do_hash", start);
+
+    let start = Instant::now();
+    let mapped_segments = hash_packets(
+        &mut MmapScanner::new(&map, None)?,
+        HashOptions::default(),
+        &mut ProgramMap::default(),
+    )?;
+    report("MmapScanner hash", start);
+    assert!(segments
+        .iter()
+        .zip(&mapped_segments)
+        .all(|(a, b)| a.hash == b.hash && a.offset == b.offset));
+    assert_eq!(segments.len(), mapped_segments.len());
+
+    println!("{packets} packets, {} segments", segments.len());
+    Ok(())
+}
+
+const VIDEO_PID: u16 = 0x100;
+const AUDIO_PID: u16 = 0x101;
+const PMT_PID: u16 = 0x1000;
+const NULL_PID: u16 = 0x1fff;
+
+/// Writes a video of about `size` bytes: segments of 25 frames of H.264
+/// video and some audio, each starting with a PAT and PMT, with random
+/// payloads.
+fn generate(path: &Path, size: u64) -> anyhow::Result<()> {
+    let mut output = BufWriter::with_capacity(1 << 20, File::create(path)?);
+    let mut generator = Generator {
+        counters: [0; 0x2000],
+        random: 0x9e3779b97f4a7c15,
+    };
+    let pat = section(0, 1, &[0, 1, 0xe0 | (PMT_PID >> 8) as u8, PMT_PID as u8]);
+    let pmt = section(
+        2,
+        1,
+        &[
+            0xe1, 0x00, 0xf0, 0, // PCR PID, no program info
+            0x1b, 0xe1, 0x00, 0xf0, 0, // H.264 video
+            0x0f, 0xe1, 0x01, 0xf0, 0, // AAC audio
+        ],
+    );
+
+    let mut written = 0;
+    let mut pts: u64 = 90000;
+    while written < size {
+        let mut packets = vec![generator.psi(0, &pat), generator.psi(PMT_PID, &pmt)];
+        for frame in 0..25 {
+            let keyframe = frame == 0;
+            packets.push(generator.video_start(pts, keyframe));
+            for _ in 0..if keyframe { 60 } else { 15 } {
+                packets.push(generator.payload(VIDEO_PID));
+            }
+            for _ in 0..2 {
+                packets.push(generator.payload(AUDIO_PID));
+            }
+            if frame % 5 == 0 {
+                packets.push(generator.payload(NULL_PID));
+            }
+            pts += 3600;
+        }
+        for packet in &packets {
+            output.write_all(packet)?;
+        }
+        written += (packets.len() * 188) as u64;
+    }
+    output.flush()?;
+    Ok(())
+}
+
+/// A PSI section with its CRC.
+fn section(table_id: u8, extension: u16, body: &[u8]) -> Vec<u8> {
+    let length = 5 + body.len() + 4;
+    let mut section = vec![
+        table_id,
+        0xb0 | (length >> 8) as u8,
+        length as u8,
+        (extension >> 8) as u8,
+        extension as u8,
+        0xc1,
+        0,
+        0,
+    ];
+    section.extend_from_slice(body);
+    let crc = crc32(&section);
+    section.extend_from_slice(&crc.to_be_bytes());
+    section
+}
+
+struct Generator {
+    counters: [u8; 0x2000],
+    random: u64,
+}
+
+impl Generator {
+    fn header(&mut self, pid: u16, start: bool, adaptation: bool) -> [u8; 188] {
+        let counter = &mut self.counters[pid as usize];
+        let mut packet = [0xffu8; 188];
+        packet[0] = 0x47;
+        packet[1] = if start { 0x40 } else { 0 } | (pid >> 8) as u8;
+        packet[2] = pid as u8;
+        packet[3] = if adaptation { 0x30 } else { 0x10 } | *counter;
+        *counter = (*counter + 1) & 0xf;
+        packet
+    }
+
+    fn psi(&mut self, pid: u16, section: &[u8]) -> [u8; 188] {
+        let mut packet = self.header(pid, true, false);
+        packet[4] = 0;
+        packet[5..5 + section.len()].copy_from_slice(section);
+        packet
+    }
+
+    fn payload(&mut self, pid: u16) -> [u8; 188] {
+        let mut packet = self.header(pid, false, false);
+        if pid != NULL_PID {
+            self.fill(&mut packet[4..]);
+        }
+        packet
+    }
+
+    /// First packet of a frame: PCR, PES header with PTS and the start of
+    /// an IDR or non-IDR slice.
+    fn video_start(&mut self, pts: u64, keyframe: bool) -> [u8; 188] {
+        let mut packet = self.header(VIDEO_PID, true, true);
+        let pcr = pts;
+        packet[4] = 7;
+        packet[5] = if keyframe { 0x50 } else { 0x10 };
+        packet[6..10].copy_from_slice(&((pcr >> 1) as u32).to_be_bytes());
+        packet[10] = ((pcr & 1) << 7) as u8 | 0x7e;
+        packet[11] = 0;
+
+        let mut pes = vec![0, 0, 1, 0xe0, 0, 0, 0x80, 0x80, 5];
+        pes.push(0x21 | ((pts >> 29) & 0x0e) as u8);
+        pes.push((pts >> 22) as u8);
+        pes.push(((pts >> 14) & 0xfe) as u8 | 1);
+        pes.push((pts >> 7) as u8);
+        pes.push(((pts << 1) & 0xfe) as u8 | 1);
+        pes.extend_from_slice(&[0, 0, 0, 1, if keyframe { 0x65 } else { 0x41 }]);
+        packet[12..12 + pes.len()].copy_from_slice(&pes);
+        self.fill(&mut packet[12 + pes.len()..]);
+        packet
+    }
+
+    /// Fills `data` with xorshift random bytes.
+    fn fill(&mut self, data: &mut [u8]) {
+        for chunk in data.chunks_mut(8) {
+            self.random ^= self.random << 13;
+            self.random ^= self.random >> 7;
+            self.random ^= self.random << 17;
+            chunk.copy_from_slice(&self.random.to_le_bytes()[..chunk.len()]);
+        }
+    }
+}
@@ -0,0 +1,574 @@
+//! Extracting the bytes of segments from a video.
+
+use crate::{
+    error::MtfError,
+    header::{payload, random_access_indicator, MpegtsHeader, NULL_PID},
+    nal::{Codec, NalScanner},
+    packet::{sync_offset, PacketReader},
+    pes::pes_payload,
+    psi::{ProgramMap, PAT_PID},
+    segment::HashFile,
+    timestamp::{Clock, PCR_HZ},
+};
+use anyhow::bail;
+use clap::ValueEnum;
+use std::{
+    collections::{HashMap, HashSet},
+    fs::File,
+    io::{BufWriter, Read, Seek, SeekFrom, Write},
+    path::Path,
+};
+
+/// Copies the bytes `from..to` of `video` to `output`, or up to the end of
+/// `video` if `to` is `None`. Returns the number of bytes written.
+pub fn cut<P, Q>(video: P, output: Q, from: u64, to: Option<u64>) -> anyhow::Result<u64>
+where
+    P: AsRef<Path>,
+    Q: AsRef<Path>,
+{
+    if let Some(to) = to.filter(|to| *to < from) {
+        bail!(MtfError::InvalidRange { from, to });
+    }
+
+    let mut file = File::open(video.as_ref())?;
+    file.seek(SeekFrom::Start(from))?;
+
+    let mut reader: Box<dyn Read> = match to {
+        Some(end) => Box::new(file.take(end - from)),
+        None => Box::new(file),
+    };
+    let writer = &mut File::create(output.as_ref())?;
+    Ok(std::io::copy(&mut reader, writer)?)
+}
+
+/// How to move a cut offset which is not at the start of a packet.
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Align {
+    /// To the start of the packet containing it
+    Down,
+    /// To the start of the next packet
+    Up,
+    /// To the closest packet start
+    Nearest,
+}
+
+/// Checks that `offset` is at the start of a packet of `video`, which is the
+/// case if sync bytes are found there and one packet later, or if it is the
+/// end of the video. Otherwise, moves it to a packet start with `align`, or
+/// fails with [`MtfError::UnalignedOffset`] if `align` is `None`. Fails with
+/// [`MtfError::OffsetOutOfRange`] if `offset` is past the end of `video`.
+pub fn align_offset<P>(
+    video: P,
+    offset: u64,
+    packet_size: usize,
+    align: Option<Align>,
+) -> anyhow::Result<u64>
+where
+    P: AsRef<Path>,
+{
+    let mut file = File::open(video.as_ref())?;
+    let length = file.metadata()?.len();
+    if offset > length {
+        bail!(MtfError::OffsetOutOfRange { offset, length });
+    }
+    if is_packet_start(&mut file, length, offset, packet_size)? {
+        return Ok(offset);
+    }
+
+    let size = packet_size as u64;
+    let mut down = None;
+    if matches!(align, Some(Align::Down | Align::Nearest)) {
+        for candidate in (offset.saturating_sub(size - 1)..offset).rev() {
+            if is_packet_start(&mut file, length, candidate, packet_size)? {
+                down = Some(candidate);
+                break;
+            }
+        }
+    }
+    let mut up = None;
+    if matches!(align, Some(Align::Up | Align::Nearest)) {
+        for candidate in offset + 1..=(offset + size - 1).min(length) {
+            if is_packet_start(&mut file, length, candidate, packet_size)? {
+                up = Some(candidate);
+                break;
+            }
+        }
+    }
+
+    let aligned = match (down, up) {
+        (Some(down), Some(up)) if up - offset < offset - down => Some(up),
+        (Some(down), _) => Some(down),
+        (None, up) => up,
+    };
+    aligned.ok_or_else(|| MtfError::UnalignedOffset(offset).into())
+}
+
+fn is_packet_start(
+    file: &mut File,
+    length: u64,
+    offset: u64,
+    packet_size: usize,
+) -> std::io::Result<bool> {
+    if offset == length {
+        return Ok(true);
+    }
+
+    let sync = sync_offset(packet_size);
+    let mut buf = Vec::with_capacity(packet_size + sync + 1);
+    file.seek(SeekFrom::Start(offset))?;
+    Read::by_ref(file)
+        .take((packet_size + sync + 1) as u64)
+        .read_to_end(&mut buf)?;
+
+    let is_last = offset + packet_size as u64 >= length;
+    Ok(buf.get(sync) == Some(&0x47) && (is_last || buf.get(packet_size + sync) == Some(&0x47)))
+}
+
+/// Copies the packets `from..to` of `video` to `output` like [`cut`], making
+/// the output playable on its own, reading packets of `packet_size` bytes or
+/// of the detected size if `None`:
+///
+/// - the PAT and PMTs in effect at `from` are inserted first,
+/// - packets of each PID are dropped until its first payload unit start, so
+///   the output does not begin in the middle of a PES packet or section,
+/// - with `rewrite_cc`, continuity counters are renumbered from 0 for each
+///   PID so that there is no discontinuity.
+///
+/// Returns the number of bytes written.
+pub fn cut_standalone<P, Q>(
+    video: P,
+    output: Q,
+    from: u64,
+    to: Option<u64>,
+    packet_size: Option<usize>,
+    rewrite_cc: bool,
+) -> anyhow::Result<u64>
+where
+    P: AsRef<Path>,
+    Q: AsRef<Path>,
+{
+    if let Some(to) = to.filter(|to| *to < from) {
+        bail!(MtfError::InvalidRange { from, to });
+    }
+
+    let mut reader = PacketReader::open(video, packet_size)?;
+    let sync = sync_offset(reader.packet_size());
+    let mut programs = ProgramMap::default();
+    // raw packets of the latest PAT and PMTs, by PID
+    let mut tables: HashMap<u16, Vec<Vec<u8>>> = HashMap::new();
+
+    let mut writer = BufWriter::new(File::create(output.as_ref())?);
+    let mut written = 0;
+    let mut started: HashSet<u16> = HashSet::new();
+    let mut counters: HashMap<u16, u8> = HashMap::new();
+    let mut inserted = false;
+
+    while let Some(packet) = reader.next_packet()? {
+        if to.is_some_and(|to| packet.offset >= to) {
+            break;
+        }
+        let header = MpegtsHeader::new(&packet.data)?;
+
+        if packet.offset < from {
+            let is_table = header.pid == PAT_PID || programs.is_pmt_pid(header.pid);
+            programs.push(&packet.data);
+            if is_table {
+                let units = tables.entry(header.pid).or_default();
+                if header.is_start {
+                    units.clear();
+                }
+                if header.is_start || !units.is_empty() {
+                    units.push(reader.raw_packet().to_vec());
+                }
+            }
+            continue;
+        }
+
+        let mut units = Vec::new();
+        if !inserted {
+            inserted = true;
+            // keep the M2TS timestamp of the first packet for inserted ones
+            let prefix = &reader.raw_packet()[..sync];
+            let pids = std::iter::once(PAT_PID).chain(
+                programs
+                    .programs()
+                    .into_iter()
+                    .map(|program| program.pmt_pid),
+            );
+            for pid in pids {
+                for unit in tables.remove(&pid).unwrap_or_default() {
+                    let mut unit = unit;
+                    unit[..sync].copy_from_slice(prefix);
+                    units.push(unit);
+                }
+                started.insert(pid);
+            }
+        }
+
+        if header.pid == NULL_PID || header.is_start || started.contains(&header.pid) {
+            started.insert(header.pid);
+            units.push(reader.raw_packet().to_vec());
+        }
+
+        for mut unit in units {
+            if rewrite_cc {
+                rewrite_continuity_counter(&mut unit[sync..sync + 4], &mut counters);
+            }
+            writer.write_all(&unit)?;
+            written += unit.len() as u64;
+        }
+    }
+
+    writer.flush()?;
+    Ok(written)
+}
+
+/// Renumbers the continuity counter of a packet header, counting from 0 for
+/// each PID. The counter only increments on packets with a payload.
+fn rewrite_continuity_counter(header: &mut [u8], counters: &mut HashMap<u16, u8>) {
+    let pid = u16::from_be_bytes([header[1], header[2]]) & 0x1fff;
+    if pid == NULL_PID {
+        return;
+    }
+
+    let has_payload = header[3] & 0x10 != 0;
+    let counter = match counters.get(&pid) {
+        Some(counter) if has_payload => (counter + 1) & 0x0f,
+        Some(counter) => *counter,
+        None => 0,
+    };
+    counters.insert(pid, counter);
+    header[3] = (header[3] & 0xf0) | counter;
+}
+
+/// Resolves a time range of `video`, in seconds from its start as printed by
+/// `mtf match`, to the offsets of the first packets at or after `start` and
+/// `end`, scanning its PCR (or PTS if there is no PCR) in packets of
+/// `packet_size` bytes, or of the detected size if `None`.
+///
+/// With `keyframe`, the start is moved back to the closest preceding video
+/// keyframe: a packet with the random access indicator, or the start of an
+/// H.264 IDR or H.265 IRAP picture. The end offset is `None` if `end` is
+/// `None` or beyond the end of the video.
+pub fn find_time_range<P>(
+    video: P,
+    start: f64,
+    end: Option<f64>,
+    keyframe: bool,
+    packet_size: Option<usize>,
+) -> anyhow::Result<(u64, Option<u64>)>
+where
+    P: AsRef<Path>,
+{
+    if end.is_some_and(|end| end < start) {
+        bail!("end time is before start time");
+    }
+
+    let mut reader = PacketReader::open(video, packet_size)?;
+    let mut programs = ProgramMap::default();
+    let mut clock = Clock::default();
+
+    let mut last_keyframe: Option<u64> = None;
+    // start offset of a video PES packet and the scanner of its first picture
+    let mut scanner: Option<(u64, NalScanner)> = None;
+    // offset of the first packet at or after `start`
+    let mut start_offset: Option<u64> = None;
+    let mut from: Option<u64> = None;
+
+    while let Some(packet) = reader.next_packet()? {
+        programs.push(&packet.data);
+        clock.push(&packet.data);
+        let time = clock.elapsed() as f64 / PCR_HZ as f64;
+
+        if from.is_none() {
+            if keyframe {
+                let header = MpegtsHeader::new(&packet.data)?;
+                let video = programs.video_stream();
+                let is_video = video.is_some_and(|stream| stream.pid == header.pid);
+                if (is_video || video.is_none()) && random_access_indicator(&packet.data) {
+                    last_keyframe = Some(packet.offset);
+                }
+
+                if is_video {
+                    let data = if header.is_start {
+                        scanner = video
+                            .and_then(|stream| Codec::from_stream_type(stream.stream_type))
+                            .map(|codec| (packet.offset, NalScanner::new(codec)));
+                        payload(&packet.data).and_then(pes_payload)
+                    } else {
+                        payload(&packet.data)
+                    };
+                    if let (Some((offset, nal_scanner)), Some(data)) = (&mut scanner, data) {
+                        if let Some(random_access) = nal_scanner.push(data) {
+                            if random_access {
+                                last_keyframe = Some(*offset);
+                            }
+                            scanner = None;
+                        }
+                    }
+                }
+            }
+
+            if start_offset.is_none() && clock.is_running() && time >= start {
+                start_offset = Some(packet.offset);
+            }
+            // a picture starting before `start` may still turn out to be a
+            // keyframe
+            if let Some(start_offset) = start_offset {
+                if !keyframe {
+                    from = Some(start_offset);
+                } else if scanner
+                    .as_ref()
+                    .is_none_or(|(offset, _)| *offset >= start_offset)
+                {
+                    from = Some(last_keyframe.unwrap_or(start_offset));
+                }
+            }
+            if from.is_some() && end.is_none() {
+                break;
+            }
+        }
+
+        if let (Some(from), Some(end)) = (from, end) {
+            if time >= end && packet.offset > from {
+                return Ok((from, Some(packet.offset)));
+            }
+        }
+    }
+
+    match from.or(start_offset) {
+        Some(from) => Ok((from, None)),
+        None => bail!("start time is beyond the end of the video"),
+    }
+}
+
+/// Resolves a time range like [`find_time_range`], using the segment times
+/// of a hash file instead of scanning the video. The range is widened to
+/// segment boundaries, so it starts at a keyframe if the hash file was split
+/// on keyframes.
+pub fn find_time_range_in(
+    hashes: &HashFile,
+    start: f64,
+    end: Option<f64>,
+) -> anyhow::Result<(u64, Option<u64>)> {
+    if end.is_some_and(|end| end < start) {
+        bail!("end time is before start time");
+    }
+    if hashes.iter().any(|segment| segment.start.is_none()) {
+        bail!("hash file has no segment times, regenerate it with `mtf hash`");
+    }
+
+    let from = hashes
+        .iter()
+        .rev()
+        .find(|segment| segment.start.unwrap() <= start)
+        .or(hashes.segments.first())
+        .map(|segment| segment.offset);
+    let Some(from) = from else {
+        bail!("hash file has no segments");
+    };
+    let to = end.and_then(|end| {
+        hashes
+            .iter()
+            .find(|segment| segment.start.unwrap() >= end)
+            .map(|segment| segment.offset)
+    });
+    Ok((from, to))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        packet::TS_PACKET_SIZE,
+        testing::{pat, pmt, timed_video, video, TempFile},
+    };
+    use std::fs;
+
+    /// Cuts `data` from packet `from` to packet `to` with
+    /// [`cut_standalone`], returning the output.
+    fn cut_packets(
+        data: &[u8],
+        size: usize,
+        from: u64,
+        to: Option<u64>,
+        rewrite_cc: bool,
+    ) -> Vec<u8> {
+        let input = TempFile::new(data);
+        let output = TempFile::new(&[]);
+        let size = size as u64;
+        let written = cut_standalone(
+            input.path(),
+            output.path(),
+            from * size,
+            to.map(|to| to * size),
+            None,
+            rewrite_cc,
+        )
+        .unwrap();
+        let output = fs::read(output.path()).unwrap();
+        assert_eq!(written, output.len() as u64);
+        output
+    }
+
+    #[test]
+    fn aligns_offsets_on_packet_starts() {
+        let file = TempFile::new(&video(&[1, 2]));
+        let align = |offset, align| align_offset(file.path(), offset, TS_PACKET_SIZE, align);
+
+        assert_eq!(align(564, None).unwrap(), 564);
+        for (offset, down, up, nearest) in [(614, 564, 752, 564), (714, 564, 752, 752)] {
+            assert_eq!(align(offset, Some(Align::Down)).unwrap(), down);
+            assert_eq!(align(offset, Some(Align::Up)).unwrap(), up);
+            assert_eq!(align(offset, Some(Align::Nearest)).unwrap(), nearest);
+        }
+
+        // the end of the video is a packet start too
+        assert_eq!(align(1880, None).unwrap(), 1880);
+        assert_eq!(align(1800, Some(Align::Up)).unwrap(), 1880);
+        assert_eq!(align(100, Some(Align::Down)).unwrap(), 0);
+    }
+
+    #[test]
+    fn rejects_unaligned_and_out_of_range_offsets() {
+        let file = TempFile::new(&video(&[1, 2]));
+        let error = align_offset(file.path(), 614, TS_PACKET_SIZE, None).unwrap_err();
+        assert!(matches!(
+            error.downcast_ref(),
+            Some(MtfError::UnalignedOffset(614))
+        ));
+
+        let error = align_offset(file.path(), 1881, TS_PACKET_SIZE, Some(Align::Down)).unwrap_err();
+        assert!(matches!(
+            error.downcast_ref(),
+            Some(MtfError::OffsetOutOfRange {
+                offset: 1881,
+                length: 1880
+            })
+        ));
+    }
+
+    /// Offset of the first packet of a frame of [`timed_video`], whose
+    /// frames are 4 packets long, after a PAT and PMT every 5 frames.
+    fn frame(frame: u64) -> u64 {
+        (frame / 5 * 22 + 2 + frame % 5 * 4) * TS_PACKET_SIZE as u64
+    }
+
+    #[test]
+    fn finds_the_packets_of_a_time_range() {
+        let file = TempFile::new(&timed_video(40, 0));
+        let find = |start, end, keyframe| find_time_range(file.path(), start, end, keyframe, None);
+
+        // frames are 40 ms apart
+        assert_eq!(
+            find(0.1, Some(0.3), false).unwrap(),
+            (frame(3), Some(frame(8)))
+        );
+        assert_eq!(find(0.3, None, true).unwrap(), (frame(5), None));
+        assert_eq!(find(0.1, Some(100.0), false).unwrap(), (frame(3), None));
+        assert!(find(100.0, None, false).is_err());
+        assert!(find(0.3, Some(0.1), false).is_err());
+    }
+
+    #[test]
+    fn finds_keyframes_from_their_pictures() {
+        // no random access indicator, only IDR slices
+        let mut data = timed_video(40, 0);
+        for packet in data.chunks_mut(TS_PACKET_SIZE) {
+            if packet[3] & 0x20 != 0 {
+                packet[5] &= !0x40;
+            }
+        }
+        let file = TempFile::new(&data);
+        assert_eq!(
+            find_time_range(file.path(), 0.5, None, true, None).unwrap(),
+            (frame(10), None)
+        );
+    }
+
+    #[test]
+    fn finds_time_ranges_from_segment_times() {
+        let file = TempFile::new(&timed_video(40, 0));
+        let hashes = HashFile::new(file.path(), Default::default()).unwrap();
+        // segments start at the PAT before every 5th frame, at the time of
+        // the frame before
+        let starts: Vec<f64> = hashes.iter().map(|s| s.start.unwrap()).collect();
+        assert!(starts[1] > 0.15 && starts[1] < 0.17, "{starts:?}");
+        let offset = |index: usize| hashes.segments[index].offset;
+
+        let find = |start, end| find_time_range_in(&hashes, start, end).unwrap();
+        assert_eq!(find(0.3, Some(0.5)), (offset(1), Some(offset(3))));
+        assert_eq!(find(0.0, None), (offset(0), None));
+        assert_eq!(find(0.3, Some(100.0)), (offset(1), None));
+        assert!(find_time_range_in(&hashes, 0.5, Some(0.3)).is_err());
+
+        // no PCR nor PTS
+        let file = TempFile::new(&video(&[1, 2]));
+        let hashes = HashFile::new(file.path(), Default::default()).unwrap();
+        let error = find_time_range_in(&hashes, 0.0, None).unwrap_err();
+        assert!(error.to_string().contains("no segment times"), "{error}");
+    }
+
+    #[test]
+    fn inserts_tables_and_starts_each_pid_on_a_unit_start() {
+        // PAT, PMT, then frames of 4 packets starting at packet 2
+        let data = timed_video(10, 0);
+        let output = cut_packets(&data, TS_PACKET_SIZE, 3, Some(30), false);
+
+        // the rest of the frame of packet 3 is dropped
+        let expected = [&pat()[..], &pmt(), &data[6 * 188..30 * 188]].concat();
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn renumbers_continuity_counters() {
+        let mut data = timed_video(10, 0);
+        // continuity counters of the original, which start anywhere
+        for (i, packet) in data.chunks_mut(TS_PACKET_SIZE).enumerate() {
+            packet[3] = packet[3] & 0xf0 | (i * 7 % 16) as u8;
+        }
+        let output = cut_packets(&data, TS_PACKET_SIZE, 3, None, true);
+
+        let mut counters: HashMap<u16, Vec<u8>> = HashMap::new();
+        for packet in output.chunks(TS_PACKET_SIZE) {
+            let pid = u16::from_be_bytes([packet[1], packet[2]]) & 0x1fff;
+            counters.entry(pid).or_default().push(packet[3] & 0x0f);
+        }
+        assert_eq!(counters.len(), 3);
+        for counters in counters.values() {
+            let expected: Vec<u8> = (0..counters.len()).map(|i| i as u8 & 0x0f).collect();
+            assert_eq!(*counters, expected);
+        }
+    }
+
+    #[test]
+    fn gives_inserted_tables_the_timestamp_of_the_cut_start() {
+        let m2ts: Vec<u8> = timed_video(10, 0)
+            .chunks(TS_PACKET_SIZE)
+            .enumerate()
+            .flat_map(|(i, packet)| [&(i as u32).to_be_bytes()[..], packet].concat())
+            .collect();
+        let output = cut_packets(&m2ts, 192, 3, Some(10), false);
+
+        let prefixes: Vec<u32> = output
+            .chunks(192)
+            .map(|packet| u32::from_be_bytes(packet[..4].try_into().unwrap()))
+            .collect();
+        // the tables are inserted where the cut starts, before the dropped
+        // rest of the frame
+        assert_eq!(prefixes, vec![3, 3, 6, 7, 8, 9]);
+        assert_eq!(&output[4..192], &pat()[..]);
+    }
+
+    #[test]
+    fn rejects_an_end_before_the_start() {
+        let input = TempFile::new(&timed_video(10, 0));
+        let output = TempFile::new(&[]);
+        let error =
+            cut_standalone(input.path(), output.path(), 940, Some(188), None, false).unwrap_err();
+        assert!(matches!(
+            error.downcast_ref(),
+            Some(MtfError::InvalidRange { from: 940, to: 188 })
+        ));
+    }
+}
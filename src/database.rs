@@ -0,0 +1,536 @@
+//! An index of many recordings, searched all at once.
+
+use crate::{
+    error::MtfError,
+    format::{read_binary, write_binary},
+    matching::{match_hashes, match_windows, query_runs, MatchResult},
+    segment::{do_hash, HashFile, HashOptions, TsSegment, HASH_VERSION},
+};
+use anyhow::bail;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fs::{self, File},
+    io::{BufReader, BufWriter, ErrorKind, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+/// A recording of a [`Database`].
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Recording {
+    /// Identifier of the recording, never reused in the database
+    pub id: u32,
+    /// The video, as recorded in its hash file
+    pub file: PathBuf,
+    /// Number of segments of the recording
+    pub segments: usize,
+}
+
+/// The recordings of a database and the options they were hashed with.
+#[derive(Serialize, Deserialize, Default)]
+struct Catalog {
+    version: u32,
+    /// Options shared by all recordings, `None` until the first one is added
+    options: Option<HashOptions>,
+    next_id: u32,
+    recordings: Vec<Recording>,
+}
+
+/// Size of an entry of the hash table: hash, recording ID, segment index and
+/// offset, little endian.
+const ENTRY_SIZE: usize = 8 + 4 + 4 + 8;
+
+/// An entry of the hash table, ordered by hash.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct Entry {
+    hash: u64,
+    recording: u32,
+    segment: u32,
+    offset: u64,
+}
+
+impl Entry {
+    fn to_bytes(self) -> [u8; ENTRY_SIZE] {
+        let mut bytes = [0u8; ENTRY_SIZE];
+        bytes[..8].copy_from_slice(&self.hash.to_le_bytes());
+        bytes[8..12].copy_from_slice(&self.recording.to_le_bytes());
+        bytes[12..16].copy_from_slice(&self.segment.to_le_bytes());
+        bytes[16..].copy_from_slice(&self.offset.to_le_bytes());
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8; ENTRY_SIZE]) -> Self {
+        Self {
+            hash: u64::from_le_bytes(bytes[..8].try_into().unwrap()),
+            recording: u32::from_le_bytes(bytes[8..12].try_into().unwrap()),
+            segment: u32::from_le_bytes(bytes[12..16].try_into().unwrap()),
+            offset: u64::from_le_bytes(bytes[16..].try_into().unwrap()),
+        }
+    }
+}
+
+/// The result of a search in one recording.
+pub struct SearchResult {
+    /// The recording containing the query
+    pub recording: Recording,
+    /// The hash file of the recording
+    pub hashes: HashFile,
+    /// Where the query is in the recording
+    pub result: MatchResult,
+}
+
+/// An index of the segments of many recordings, searched all at once.
+///
+/// It is a directory holding `catalog.json` with the list of recordings, the
+/// hash file of each recording in the binary format under `recordings/`, and
+/// `hashes.idx`, the table of the segment hashes of all recordings sorted by
+/// hash, which is binary searched without loading it.
+///
+/// All recordings must be hashed with the same options, so that a query is
+/// hashed only once.
+pub struct Database {
+    path: PathBuf,
+    catalog: Catalog,
+}
+
+impl Database {
+    /// Opens the database at `path`, which is empty if it does not exist
+    /// yet.
+    pub fn open<P>(path: P) -> anyhow::Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        let path = path.as_ref().to_path_buf();
+        let catalog = match File::open(path.join("catalog.json")) {
+            Ok(file) => {
+                let catalog: Catalog = serde_json::from_reader(BufReader::new(file))?;
+                if catalog.version != HASH_VERSION && !catalog.recordings.is_empty() {
+                    bail!(MtfError::HashVersionMismatch {
+                        found: catalog.version,
+                        expected: HASH_VERSION,
+                    });
+                }
+                catalog
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => Catalog::default(),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Self { path, catalog })
+    }
+
+    /// The recordings of the database, in the order they were added.
+    pub fn recordings(&self) -> &[Recording] {
+        &self.catalog.recordings
+    }
+
+    /// The options all recordings were hashed with, `None` if the database
+    /// is empty.
+    pub fn options(&self) -> Option<&HashOptions> {
+        self.catalog.options.as_ref()
+    }
+
+    /// Loads the hash file of a recording.
+    pub fn load(&self, recording: &Recording) -> anyhow::Result<HashFile> {
+        read_binary(BufReader::new(File::open(
+            self.recording_path(recording.id),
+        )?))
+    }
+
+    /// Adds the recording of a hash file.
+    pub fn add(&mut self, hashes: &HashFile) -> anyhow::Result<Recording> {
+        let options = index_options(hashes);
+        if let Some(expected) = self.catalog.options.as_ref().filter(|o| **o != options) {
+            let (serde_json::Value::Object(found), serde_json::Value::Object(expected)) = (
+                serde_json::to_value(&options)?,
+                serde_json::to_value(expected)?,
+            ) else {
+                unreachable!("options are serialized as maps");
+            };
+            let differences: Vec<String> = found
+                .iter()
+                .filter(|(name, value)| expected.get(*name) != Some(value))
+                .map(|(name, value)| format!("{name} {value} instead of {}", expected[name]))
+                .collect();
+            bail!(
+                "{} was hashed with other options than the index: {}",
+                hashes.file.display(),
+                differences.join(", ")
+            );
+        }
+        if let Some(recording) = self.find(&hashes.file.to_string_lossy()) {
+            bail!(
+                "{} is already indexed as recording {}, remove it first",
+                hashes.file.display(),
+                recording.id
+            );
+        }
+
+        let recording = Recording {
+            id: self.catalog.next_id,
+            file: hashes.file.clone(),
+            segments: hashes.len(),
+        };
+        fs::create_dir_all(self.path.join("recordings"))?;
+        write_binary(
+            hashes,
+            BufWriter::new(File::create(self.recording_path(recording.id))?),
+        )?;
+
+        let mut entries: Vec<Entry> = hashes
+            .iter()
+            .enumerate()
+            .map(|(index, segment)| Entry {
+                hash: segment.hash,
+                recording: recording.id,
+                segment: index as u32,
+                offset: segment.offset,
+            })
+            .collect();
+        entries.sort_unstable();
+        self.rewrite_table(|_| true, entries)?;
+
+        self.catalog.version = HASH_VERSION;
+        self.catalog.options = Some(options);
+        self.catalog.next_id += 1;
+        self.catalog.recordings.push(recording.clone());
+        self.save_catalog()?;
+        Ok(recording)
+    }
+
+    /// Removes a recording, given by ID or video path.
+    pub fn remove(&mut self, recording: &str) -> anyhow::Result<Recording> {
+        let Some(recording) = self.find(recording).cloned() else {
+            bail!("recording {recording} is not in the index");
+        };
+        self.rewrite_table(|entry| entry.recording != recording.id, Vec::new())?;
+        fs::remove_file(self.recording_path(recording.id))?;
+
+        self.catalog
+            .recordings
+            .retain(|other| other.id != recording.id);
+        if self.catalog.recordings.is_empty() {
+            self.catalog.options = None;
+        }
+        self.save_catalog()?;
+        Ok(recording)
+    }
+
+    /// Finds the runs of segments of all recordings which are identical to
+    /// the video `segment`, like [`crate::find_segment`].
+    pub fn search<P>(&self, segment: P) -> anyhow::Result<Vec<SearchResult>>
+    where
+        P: AsRef<Path>,
+    {
+        let segment = segment.as_ref();
+        let Some(options) = &self.catalog.options else {
+            return Ok(Vec::new());
+        };
+        if options.split_on.is_fixed() {
+            // the query does not start on a window, so the table of hashes
+            // cannot give candidates; search the windows of each recording
+            let mut results = Vec::new();
+            for recording in &self.catalog.recordings {
+                let hashes = self.load(recording)?;
+                let result = match_windows(&hashes, segment)?;
+                if !result.indices.is_empty() {
+                    results.push(SearchResult {
+                        recording: recording.clone(),
+                        hashes,
+                        result,
+                    });
+                }
+            }
+            return Ok(results);
+        }
+        let segment_hashes = do_hash(segment, options.clone())?;
+
+        // the whole query, or the run of its whole segments, as with
+        // `find_segment`
+        for (run, end) in query_runs(&segment_hashes) {
+            let results = self.search_run(segment, run, end)?;
+            if !results.is_empty() {
+                return Ok(results);
+            }
+        }
+        Ok(Vec::new())
+    }
+
+    /// Searches a run of the segments of a query ending at offset `end` of
+    /// it, or with it if `None`.
+    fn search_run(
+        &self,
+        segment: &Path,
+        run: &[TsSegment],
+        end: Option<u64>,
+    ) -> anyhow::Result<Vec<SearchResult>> {
+        // candidate starts from the first hash, then whole runs per recording
+        let mut starts: BTreeMap<u32, Vec<usize>> = BTreeMap::new();
+        for entry in self.lookup(run[0].hash)? {
+            starts
+                .entry(entry.recording)
+                .or_default()
+                .push(entry.segment as usize);
+        }
+
+        let mut results = Vec::new();
+        for (id, starts) in starts {
+            let Some(recording) = self.catalog.recordings.iter().find(|r| r.id == id) else {
+                continue;
+            };
+            let hashes = self.load(recording)?;
+            let result = match_hashes(&hashes, segment, run, end, starts)?;
+            if !result.indices.is_empty() {
+                results.push(SearchResult {
+                    recording: recording.clone(),
+                    hashes,
+                    result,
+                });
+            }
+        }
+        Ok(results)
+    }
+
+    fn find(&self, recording: &str) -> Option<&Recording> {
+        let id = recording.parse::<u32>().ok();
+        self.catalog
+            .recordings
+            .iter()
+            .find(|other| Some(other.id) == id || other.file == Path::new(recording))
+    }
+
+    fn recording_path(&self, id: u32) -> PathBuf {
+        self.path.join("recordings").join(format!("{id}.bin"))
+    }
+
+    fn table_path(&self) -> PathBuf {
+        self.path.join("hashes.idx")
+    }
+
+    /// Entries of the hash table with the given hash, by binary search.
+    fn lookup(&self, hash: u64) -> anyhow::Result<Vec<Entry>> {
+        let mut file = match File::open(self.table_path()) {
+            Ok(file) => file,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+        let count = file.metadata()?.len() / ENTRY_SIZE as u64;
+        let mut read_entry = |index: u64| -> std::io::Result<Entry> {
+            let mut bytes = [0u8; ENTRY_SIZE];
+            file.seek(SeekFrom::Start(index * ENTRY_SIZE as u64))?;
+            file.read_exact(&mut bytes)?;
+            Ok(Entry::from_bytes(&bytes))
+        };
+
+        // first entry with a hash not lower than `hash`
+        let (mut low, mut high) = (0, count);
+        while low < high {
+            let middle = low + (high - low) / 2;
+            if read_entry(middle)?.hash < hash {
+                low = middle + 1;
+            } else {
+                high = middle;
+            }
+        }
+
+        let mut entries = Vec::new();
+        for index in low..count {
+            let entry = read_entry(index)?;
+            if entry.hash != hash {
+                break;
+            }
+            entries.push(entry);
+        }
+        Ok(entries)
+    }
+
+    /// Rewrites the hash table with the existing entries accepted by `keep`
+    /// and the sorted `entries`.
+    fn rewrite_table<F>(&self, keep: F, entries: Vec<Entry>) -> anyhow::Result<()>
+    where
+        F: Fn(&Entry) -> bool,
+    {
+        let path = self.table_path();
+        let temp = path.with_extension("idx.tmp");
+        let mut output = BufWriter::new(File::create(&temp)?);
+        let mut entries = entries.into_iter().peekable();
+
+        match File::open(&path) {
+            Ok(file) => {
+                let mut input = BufReader::new(file);
+                let mut bytes = [0u8; ENTRY_SIZE];
+                loop {
+                    match input.read_exact(&mut bytes) {
+                        Ok(()) => {}
+                        Err(e) if e.kind() == ErrorKind::UnexpectedEof => break,
+                        Err(e) => return Err(e.into()),
+                    }
+                    let existing = Entry::from_bytes(&bytes);
+                    if !keep(&existing) {
+                        continue;
+                    }
+                    while let Some(entry) = entries.next_if(|entry| *entry < existing) {
+                        output.write_all(&entry.to_bytes())?;
+                    }
+                    output.write_all(&bytes)?;
+                }
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+        for entry in entries {
+            output.write_all(&entry.to_bytes())?;
+        }
+
+        output.flush()?;
+        drop(output);
+        fs::rename(temp, path)?;
+        Ok(())
+    }
+
+    fn save_catalog(&self) -> anyhow::Result<()> {
+        fs::create_dir_all(&self.path)?;
+        let path = self.path.join("catalog.json");
+        let temp = path.with_extension("json.tmp");
+        fs::write(&temp, serde_json::to_string_pretty(&self.catalog)?)?;
+        fs::rename(temp, path)?;
+        Ok(())
+    }
+}
+
+/// The options of a hash file which decide its segment hashes, the packet
+/// size left out as packets are hashed without M2TS prefix or FEC parity.
+fn index_options(hashes: &HashFile) -> HashOptions {
+    HashOptions {
+        sketch: false,
+        per_stream: false,
+        ..hashes.query_options()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        segment::Fingerprint,
+        testing::{video, TempDir, TempFile},
+    };
+
+    fn entry(hash: u64, recording: u32, segment: u32) -> Entry {
+        Entry {
+            hash,
+            recording,
+            segment,
+            offset: segment as u64 * 940,
+        }
+    }
+
+    /// All entries of the hash table.
+    fn table(database: &Database) -> Vec<Entry> {
+        fs::read(database.table_path())
+            .unwrap()
+            .chunks_exact(ENTRY_SIZE)
+            .map(|bytes| Entry::from_bytes(bytes.try_into().unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn merges_entries_into_the_table() {
+        let dir = TempDir::new();
+        let database = Database::open(dir.path()).unwrap();
+        let first: Vec<Entry> = [5, 1, 9, 5, 3]
+            .into_iter()
+            .enumerate()
+            .map(|(i, hash)| entry(hash, 0, i as u32))
+            .collect();
+        let mut sorted = first.clone();
+        sorted.sort_unstable();
+        database.rewrite_table(|_| true, sorted).unwrap();
+
+        let second = vec![
+            entry(0, 1, 0),
+            entry(5, 1, 1),
+            entry(7, 1, 2),
+            entry(12, 1, 3),
+        ];
+        database
+            .rewrite_table(|entry| entry.segment != 2, second.clone())
+            .unwrap();
+
+        let mut expected: Vec<Entry> = first
+            .into_iter()
+            .filter(|entry| entry.segment != 2)
+            .chain(second)
+            .collect();
+        expected.sort_unstable();
+        assert_eq!(table(&database), expected);
+        assert!(!database.table_path().with_extension("idx.tmp").exists());
+    }
+
+    #[test]
+    fn looks_up_every_entry_of_a_hash() {
+        let dir = TempDir::new();
+        let database = Database::open(dir.path()).unwrap();
+        assert!(database.lookup(1).unwrap().is_empty());
+
+        // runs of equal hashes at the ends and in the middle of the table
+        let mut entries: Vec<Entry> = (0..50)
+            .map(|i| entry([2, 2, 2, 4, 6, 6, 8, 10, 10, 10][i % 10] * 10, 0, i as u32))
+            .collect();
+        entries.sort_unstable();
+        database.rewrite_table(|_| true, entries.clone()).unwrap();
+
+        for hash in 0..=110 {
+            let expected: Vec<Entry> = entries
+                .iter()
+                .filter(|entry| entry.hash == hash)
+                .copied()
+                .collect();
+            assert_eq!(database.lookup(hash).unwrap(), expected, "hash {hash}");
+        }
+    }
+
+    #[test]
+    fn adds_searches_and_removes_recordings() {
+        let dir = TempDir::new();
+        let first = TempFile::new(&video(&[1, 2, 3, 4]));
+        let second = TempFile::new(&video(&[9, 2, 3, 8, 2, 3]));
+        let query = TempFile::new(&video(&[2, 3]));
+
+        let mut database = Database::open(dir.path()).unwrap();
+        for file in [&first, &second] {
+            let hashes = HashFile::new(file.path(), HashOptions::default()).unwrap();
+            database.add(&hashes).unwrap();
+        }
+        let hashes = HashFile::new(first.path(), HashOptions::default()).unwrap();
+        assert!(database.add(&hashes).is_err(), "added twice");
+        let content = HashOptions {
+            fingerprint: Fingerprint::Content,
+            ..Default::default()
+        };
+        let hashes = HashFile::new(query.path(), content).unwrap();
+        let error = database.add(&hashes).unwrap_err();
+        assert!(error.to_string().contains("other options"), "{error}");
+
+        // reopened from disk
+        let mut database = Database::open(dir.path()).unwrap();
+        assert_eq!(database.recordings().len(), 2);
+        let found = |database: &Database| -> Vec<(u32, Vec<usize>)> {
+            database
+                .search(query.path())
+                .unwrap()
+                .into_iter()
+                .map(|found| (found.recording.id, found.result.indices))
+                .collect()
+        };
+        assert_eq!(found(&database), vec![(0, vec![1]), (1, vec![1, 4])]);
+
+        assert_eq!(database.remove("0").unwrap().id, 0);
+        assert_eq!(found(&database), vec![(1, vec![1, 4])]);
+        assert!(database.remove("0").is_err());
+
+        database.remove(&second.path().to_string_lossy()).unwrap();
+        assert!(found(&database).is_empty());
+        assert!(database.options().is_none());
+        assert!(table(&database).is_empty());
+    }
+}
@@ -0,0 +1,91 @@
+//! Errors with their own exit code.
+
+use std::fmt;
+
+/// Failures which scripts may want to tell apart, each with its own exit code.
+///
+/// They are raised through `anyhow` like every other error and recovered with
+/// `downcast_ref` in `main`. Any other error exits with code 1.
+#[derive(Debug)]
+pub enum MtfError {
+    /// A packet does not start with the 0x47 sync byte, or the video
+    /// contains no packet at all
+    BadSync,
+    /// The video contains no PAT, so it cannot be split into segments
+    NoPatFound,
+    /// The hash file was generated with an unsupported hashing scheme
+    HashVersionMismatch {
+        /// Version of the hash file
+        found: u32,
+        /// Version supported, [`crate::HASH_VERSION`]
+        expected: u32,
+    },
+    /// No segment of the hash file matches
+    SegmentNotFound,
+    /// A PSI section is malformed or fails its CRC check
+    InvalidSection(&'static str),
+    /// The video contains no keyframe to split it into segments
+    NoKeyframeFound,
+    /// A cut offset is not at the start of a packet
+    UnalignedOffset(u64),
+    /// The end of a cut is before its start
+    InvalidRange {
+        /// Start offset of the cut
+        from: u64,
+        /// End offset of the cut
+        to: u64,
+    },
+    /// A cut offset is past the end of the video
+    OffsetOutOfRange {
+        /// The offset of the cut
+        offset: u64,
+        /// Length of the video in bytes
+        length: u64,
+    },
+}
+
+impl MtfError {
+    /// Exit code of the command line tool for this failure.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            MtfError::BadSync => 3,
+            MtfError::NoPatFound => 5,
+            MtfError::HashVersionMismatch { .. } => 7,
+            MtfError::SegmentNotFound => 8,
+            MtfError::InvalidSection(_) => 9,
+            MtfError::NoKeyframeFound => 10,
+            MtfError::UnalignedOffset(_) => 11,
+            MtfError::InvalidRange { .. } => 12,
+            MtfError::OffsetOutOfRange { .. } => 13,
+        }
+    }
+}
+
+impl fmt::Display for MtfError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MtfError::BadSync => write!(f, "sync byte not found"),
+            MtfError::NoPatFound => write!(f, "no PAT found"),
+            MtfError::HashVersionMismatch { found, expected } => write!(
+                f,
+                "hash file version {found} is not supported (expected {expected}), regenerate it with `mtf hash`"
+            ),
+            MtfError::SegmentNotFound => write!(f, "segment not found"),
+            MtfError::InvalidSection(reason) => write!(f, "invalid PSI section: {reason}"),
+            MtfError::NoKeyframeFound => write!(f, "no keyframe found"),
+            MtfError::UnalignedOffset(offset) => write!(
+                f,
+                "offset {offset} is not at the start of a packet, use --align to move it"
+            ),
+            MtfError::InvalidRange { from, to } => {
+                write!(f, "end offset {to} is before start offset {from}")
+            }
+            MtfError::OffsetOutOfRange { offset, length } => write!(
+                f,
+                "offset {offset} is past the end of the video ({length} bytes)"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for MtfError {}
@@ -0,0 +1,152 @@
+//! Hashing videos while they are being recorded.
+
+use crate::segment::{HashFile, HashOptions};
+use std::{
+    fs,
+    ops::Range,
+    path::Path,
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Delay between two checks of the size of a followed video.
+pub const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Hashes a video while it is being written, like `tail -f`.
+///
+/// The video is checked every [`POLL_INTERVAL`] and the new data hashed with
+/// [`HashFile::append`]. `on_update` is called with the hash file and the
+/// range of segments completed since the last call, a segment being
+/// complete once the next one starts. Following stops once the video has
+/// not grown for `idle_timeout`, the last segment is then complete too.
+///
+/// The video does not need to exist yet, nor to contain a whole segment,
+/// errors are only returned if it still cannot be hashed after
+/// `idle_timeout`.
+pub fn follow<P, F>(
+    video: P,
+    options: HashOptions,
+    idle_timeout: Duration,
+    mut on_update: F,
+) -> anyhow::Result<HashFile>
+where
+    P: AsRef<Path>,
+    F: FnMut(&HashFile, Range<usize>) -> anyhow::Result<()>,
+{
+    let video = video.as_ref();
+    let mut size = None;
+    let mut last_growth = Instant::now();
+    let mut hashes: Option<HashFile> = None;
+    let mut completed = 0;
+
+    loop {
+        let current_size = fs::metadata(video).map(|metadata| metadata.len()).ok();
+        if current_size != size {
+            size = current_size;
+            last_growth = Instant::now();
+
+            let update = match &mut hashes {
+                Some(hashes) => hashes.append().map(|_| ()),
+                None => HashFile::new(video, options.clone()).map(|new| hashes = Some(new)),
+            };
+            match update {
+                Ok(()) => {}
+                // the video may not have a whole packet or segment yet
+                Err(_) if hashes.is_none() => {}
+                Err(e) => return Err(e),
+            }
+
+            if let Some(hashes) = &hashes {
+                let end = hashes.len().saturating_sub(1);
+                if end > completed {
+                    on_update(hashes, completed..end)?;
+                    completed = end;
+                }
+            }
+        } else if last_growth.elapsed() >= idle_timeout {
+            // the writer stopped
+            return match hashes {
+                Some(hashes) => {
+                    on_update(&hashes, completed..hashes.len())?;
+                    Ok(hashes)
+                }
+                None => HashFile::new(video, options),
+            };
+        }
+
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        packet::TS_PACKET_SIZE,
+        segment::SplitOn,
+        testing::{timed_video, TempFile},
+    };
+    use std::io::Write;
+
+    /// Follows a video written in a few steps, checking that it gives the
+    /// same segments as hashing it at once and reports each one once.
+    fn follow_growing_video(options: HashOptions) {
+        let data = timed_video(40, 10);
+        let file = TempFile::new(&data);
+        let expected = HashFile::new(file.path(), options.clone()).unwrap();
+        fs::write(file.path(), []).unwrap();
+
+        let path = file.path().to_path_buf();
+        let writer = {
+            let data = data.clone();
+            thread::spawn(move || {
+                // appended like a recorder does, never truncated
+                let mut file = fs::OpenOptions::new().append(true).open(&path).unwrap();
+                let mut written = 0;
+                for packets in [13, 41, 90, data.len() / TS_PACKET_SIZE] {
+                    file.write_all(&data[written..packets * TS_PACKET_SIZE])
+                        .unwrap();
+                    written = packets * TS_PACKET_SIZE;
+                    thread::sleep(POLL_INTERVAL + POLL_INTERVAL / 5);
+                }
+            })
+        };
+
+        let mut reported = Vec::new();
+        let hashes = follow(
+            file.path(),
+            options,
+            POLL_INTERVAL * 3,
+            |hashes, completed| {
+                assert_eq!(completed.start, reported.len());
+                reported.extend(hashes.segments[completed].iter().map(|s| s.hash));
+                Ok(())
+            },
+        )
+        .unwrap();
+        writer.join().unwrap();
+
+        let hash = |hashes: &HashFile| -> Vec<(u64, u64)> {
+            hashes.iter().map(|s| (s.offset, s.hash)).collect()
+        };
+        assert_eq!(hash(&hashes), hash(&expected));
+        assert_eq!(
+            reported,
+            expected.iter().map(|s| s.hash).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn follows_a_capture_starting_mid_stream() {
+        follow_growing_video(HashOptions::default());
+    }
+
+    #[test]
+    fn follows_a_video_split_every_few_seconds() {
+        follow_growing_video(HashOptions {
+            split_on: SplitOn::Seconds,
+            split_interval: Some(0.3),
+            ..Default::default()
+        });
+    }
+}
@@ -0,0 +1,318 @@
+//! Reading and writing hash files as JSON or in a compact binary format.
+
+use crate::{
+    hasher::Algorithm,
+    segment::{HashFile, TsSegment},
+    sketch::SKETCH_SIZE,
+};
+use anyhow::bail;
+use clap::ValueEnum;
+use std::{
+    collections::BTreeSet,
+    io::{BufRead, Read, Write},
+};
+
+/// Encodings of hash files.
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Format {
+    /// Pretty-printed JSON
+    #[default]
+    Json,
+    /// Compact binary index with fixed-width segment records
+    Binary,
+}
+
+/// Magic bytes at the start of binary hash files.
+pub const MAGIC: &[u8; 8] = b"MTFINDEX";
+
+/// Version of the binary layout, independent of the hashing scheme version.
+pub const FORMAT_VERSION: u32 = 1;
+
+const FLAG_SKETCH: u8 = 1;
+const FLAG_PER_STREAM: u8 = 2;
+
+/// Detects the format of a hash file from its first bytes, without
+/// consuming them.
+pub fn detect_format<R>(reader: &mut R) -> std::io::Result<Format>
+where
+    R: BufRead,
+{
+    Ok(if reader.fill_buf()?.starts_with(MAGIC) {
+        Format::Binary
+    } else {
+        Format::Json
+    })
+}
+
+/// Writes a hash file in the binary format.
+///
+/// All integers are little endian. The header holds, in order: [`MAGIC`],
+/// [`FORMAT_VERSION`] (u32), the hashing scheme version (u32), the packet
+/// size (u32), the algorithm (u8), flags (u8, 1 for sketches and 2 for
+/// per-stream hashes), 2 reserved bytes, the number of segments (u64), the
+/// size of a segment record (u32), the number of PIDs with per-stream hashes
+/// (u32) and these PIDs (u16 each), then the length (u32) and JSON of the
+/// other fields of the hash file.
+///
+/// Each segment record then holds the hash, offset, start, duration, first
+/// and last PTS (8 bytes each, NaN or `u64::MAX` if unknown), followed with
+/// sketches by the sketch length (u8) and [`SKETCH_SIZE`] hashes, and with
+/// per-stream hashes by a presence byte and a hash for each PID of the
+/// header.
+pub fn write_binary<W>(hashes: &HashFile, mut writer: W) -> anyhow::Result<()>
+where
+    W: Write,
+{
+    let pids: Vec<u16> = hashes
+        .iter()
+        .flat_map(|segment| segment.streams.keys().copied())
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .collect();
+    let metadata = serde_json::to_vec(&HashFile {
+        segments: Vec::new(),
+        ..hashes.clone()
+    })?;
+
+    let mut flags = 0;
+    if hashes.sketch {
+        flags |= FLAG_SKETCH;
+    }
+    if hashes.per_stream {
+        flags |= FLAG_PER_STREAM;
+    }
+
+    writer.write_all(MAGIC)?;
+    writer.write_all(&FORMAT_VERSION.to_le_bytes())?;
+    writer.write_all(&hashes.version.to_le_bytes())?;
+    writer.write_all(&(hashes.packet_size as u32).to_le_bytes())?;
+    writer.write_all(&[algorithm_id(hashes.algorithm), flags, 0, 0])?;
+    writer.write_all(&(hashes.len() as u64).to_le_bytes())?;
+    writer.write_all(&(record_size(flags, pids.len()) as u32).to_le_bytes())?;
+    writer.write_all(&(pids.len() as u32).to_le_bytes())?;
+    for pid in &pids {
+        writer.write_all(&pid.to_le_bytes())?;
+    }
+    writer.write_all(&(metadata.len() as u32).to_le_bytes())?;
+    writer.write_all(&metadata)?;
+
+    let mut record = Vec::with_capacity(record_size(flags, pids.len()));
+    for segment in hashes.iter() {
+        record.clear();
+        record.extend_from_slice(&segment.hash.to_le_bytes());
+        record.extend_from_slice(&segment.offset.to_le_bytes());
+        record.extend_from_slice(&segment.start.unwrap_or(f64::NAN).to_le_bytes());
+        record.extend_from_slice(&segment.duration.unwrap_or(f64::NAN).to_le_bytes());
+        record.extend_from_slice(&segment.first_pts.unwrap_or(u64::MAX).to_le_bytes());
+        record.extend_from_slice(&segment.last_pts.unwrap_or(u64::MAX).to_le_bytes());
+        if flags & FLAG_SKETCH != 0 {
+            record.push(segment.sketch.len() as u8);
+            for i in 0..SKETCH_SIZE {
+                let hash = segment.sketch.get(i).copied().unwrap_or_default();
+                record.extend_from_slice(&hash.to_le_bytes());
+            }
+        }
+        if flags & FLAG_PER_STREAM != 0 {
+            for pid in &pids {
+                let hash = segment.streams.get(pid);
+                record.push(hash.is_some() as u8);
+                record.extend_from_slice(&hash.copied().unwrap_or_default().to_le_bytes());
+            }
+        }
+        writer.write_all(&record)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Reads a hash file in the binary format, see [`write_binary`].
+pub fn read_binary<R>(mut reader: R) -> anyhow::Result<HashFile>
+where
+    R: Read,
+{
+    let mut header = [0u8; 40];
+    reader.read_exact(&mut header)?;
+    if &header[..8] != MAGIC {
+        bail!("not a binary hash file");
+    }
+    let format_version = u32_at(&header, 8);
+    if format_version != FORMAT_VERSION {
+        bail!(
+            "binary hash file format {format_version} is not supported (expected {FORMAT_VERSION})"
+        );
+    }
+    let version = u32_at(&header, 12);
+    let packet_size = u32_at(&header, 16) as usize;
+    let Some(algorithm) = algorithm_from_id(header[20]) else {
+        bail!("unknown hash algorithm {}", header[20]);
+    };
+    let flags = header[21];
+    let count = u64_at(&header, 24);
+    let size = u32_at(&header, 32) as usize;
+    let pid_count = u32_at(&header, 36) as u64;
+    if pid_count > 0x2000 {
+        bail!("invalid number of PIDs {pid_count}");
+    }
+
+    // lengths from the file are not trusted to allocate buffers, which
+    // grow as the data is read instead
+    let pids: Vec<u16> = read_vec(&mut reader, pid_count * 2)?
+        .chunks_exact(2)
+        .map(|pid| u16::from_le_bytes([pid[0], pid[1]]))
+        .collect();
+    if size != record_size(flags, pids.len()) {
+        bail!("invalid segment record size {size}");
+    }
+
+    let mut length = [0u8; 4];
+    reader.read_exact(&mut length)?;
+    let metadata = read_vec(&mut reader, u32::from_le_bytes(length) as u64)?;
+    let mut hashes: HashFile = serde_json::from_slice(&metadata)?;
+    hashes.version = version;
+    hashes.packet_size = packet_size;
+    hashes.algorithm = algorithm;
+    hashes.sketch = flags & FLAG_SKETCH != 0;
+    hashes.per_stream = flags & FLAG_PER_STREAM != 0;
+
+    let mut record = vec![0u8; size];
+    for _ in 0..count {
+        reader.read_exact(&mut record)?;
+        let float = |at| Some(f64::from_bits(u64_at(&record, at))).filter(|value| !value.is_nan());
+        let pts = |at| Some(u64_at(&record, at)).filter(|value| *value != u64::MAX);
+        let mut segment = TsSegment {
+            hash: u64_at(&record, 0),
+            offset: u64_at(&record, 8),
+            start: float(16),
+            duration: float(24),
+            first_pts: pts(32),
+            last_pts: pts(40),
+            sketch: Vec::new(),
+            streams: Default::default(),
+        };
+
+        let mut at = 48;
+        if hashes.sketch {
+            let len = (record[at] as usize).min(SKETCH_SIZE);
+            segment.sketch = (0..len).map(|i| u64_at(&record, at + 1 + i * 8)).collect();
+            at += 1 + SKETCH_SIZE * 8;
+        }
+        if hashes.per_stream {
+            for pid in &pids {
+                if record[at] != 0 {
+                    segment.streams.insert(*pid, u64_at(&record, at + 1));
+                }
+                at += 9;
+            }
+        }
+        hashes.segments.push(segment);
+    }
+    Ok(hashes)
+}
+
+/// Reads exactly `length` bytes.
+fn read_vec<R>(reader: &mut R, length: u64) -> anyhow::Result<Vec<u8>>
+where
+    R: Read,
+{
+    let mut data = Vec::new();
+    reader.take(length).read_to_end(&mut data)?;
+    if (data.len() as u64) < length {
+        bail!("truncated binary hash file");
+    }
+    Ok(data)
+}
+
+fn record_size(flags: u8, pids: usize) -> usize {
+    let mut size = 6 * 8;
+    if flags & FLAG_SKETCH != 0 {
+        size += 1 + SKETCH_SIZE * 8;
+    }
+    if flags & FLAG_PER_STREAM != 0 {
+        size += pids * 9;
+    }
+    size
+}
+
+fn algorithm_id(algorithm: Algorithm) -> u8 {
+    match algorithm {
+        Algorithm::Xxh64 => 0,
+        Algorithm::Fnv1a => 1,
+        Algorithm::Sha256 => 2,
+    }
+}
+
+fn algorithm_from_id(id: u8) -> Option<Algorithm> {
+    match id {
+        0 => Some(Algorithm::Xxh64),
+        1 => Some(Algorithm::Fnv1a),
+        2 => Some(Algorithm::Sha256),
+        _ => None,
+    }
+}
+
+fn u32_at(data: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes(data[at..at + 4].try_into().unwrap())
+}
+
+fn u64_at(data: &[u8], at: usize) -> u64 {
+    u64::from_le_bytes(data[at..at + 8].try_into().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        segment::HashOptions,
+        testing::{video, TempFile},
+    };
+
+    fn binary() -> (HashFile, Vec<u8>) {
+        let file = TempFile::new(&video(&[1, 2, 3]));
+        let options = HashOptions {
+            sketch: true,
+            per_stream: true,
+            ..Default::default()
+        };
+        let hashes = HashFile::new(file.path(), options).unwrap();
+        let mut data = Vec::new();
+        write_binary(&hashes, &mut data).unwrap();
+        (hashes, data)
+    }
+
+    #[test]
+    fn round_trips() {
+        let (hashes, data) = binary();
+        let read = read_binary(&data[..]).unwrap();
+        assert_eq!(read.len(), hashes.len());
+        for (a, b) in read.iter().zip(hashes.iter()) {
+            assert_eq!((a.hash, a.offset), (b.hash, b.offset));
+            assert_eq!(a.sketch, b.sketch);
+            assert_eq!(a.streams, b.streams);
+        }
+    }
+
+    #[test]
+    fn rejects_lengths_past_the_end_of_the_file() {
+        let (_, data) = binary();
+        let pid_count = u32_at(&data, 36) as usize;
+        let metadata_at = 40 + pid_count * 2;
+        for (at, value) in [
+            (36, u32::MAX.to_le_bytes().to_vec()),
+            (36, 0x2000u32.to_le_bytes().to_vec()),
+            (metadata_at, u32::MAX.to_le_bytes().to_vec()),
+            (24, u64::MAX.to_le_bytes().to_vec()),
+        ] {
+            let mut data = data.clone();
+            data[at..at + value.len()].copy_from_slice(&value);
+            assert!(read_binary(&data[..]).is_err());
+        }
+    }
+
+    #[test]
+    fn rejects_truncated_files() {
+        let (_, data) = binary();
+        for length in [0, 20, 45, data.len() - 1] {
+            assert!(read_binary(&data[..length]).is_err(), "{length}");
+        }
+    }
+}
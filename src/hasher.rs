@@ -0,0 +1,391 @@
+//! Hash algorithms for segment hashes.
+
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use std::hash::Hasher;
+
+/// Hash algorithms available for segment hashes.
+///
+/// Unlike `std::collections::hash_map::DefaultHasher`, all of these are
+/// implemented here and produce the same output on every platform and
+/// toolchain, so hash files stay valid across upgrades.
+#[derive(Serialize, Deserialize, ValueEnum, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Algorithm {
+    /// xxHash64 with seed 0
+    #[default]
+    Xxh64,
+    /// 64-bit FNV-1a
+    Fnv1a,
+    /// First 8 bytes (big endian) of SHA-256
+    Sha256,
+}
+
+impl Algorithm {
+    /// A new hasher of this algorithm.
+    pub fn hasher(self) -> Box<dyn Hasher> {
+        match self {
+            Algorithm::Xxh64 => Box::new(Xxh64::new(0)),
+            Algorithm::Fnv1a => Box::new(Fnv1a::new()),
+            Algorithm::Sha256 => Box::new(Sha256::new()),
+        }
+    }
+}
+
+const PRIME64_1: u64 = 0x9E3779B185EBCA87;
+const PRIME64_2: u64 = 0xC2B2AE3D27D4EB4F;
+const PRIME64_3: u64 = 0x165667B19E3779F9;
+const PRIME64_4: u64 = 0x85EBCA77C2B2AE63;
+const PRIME64_5: u64 = 0x27D4EB2F165667C5;
+
+/// The 64-bit variant of xxHash.
+pub struct Xxh64 {
+    seed: u64,
+    acc: [u64; 4],
+    buf: [u8; 32],
+    buf_len: usize,
+    total_len: u64,
+}
+
+impl Xxh64 {
+    /// A hasher whose output depends on `seed`.
+    pub fn new(seed: u64) -> Self {
+        Self {
+            seed,
+            acc: [
+                seed.wrapping_add(PRIME64_1).wrapping_add(PRIME64_2),
+                seed.wrapping_add(PRIME64_2),
+                seed,
+                seed.wrapping_sub(PRIME64_1),
+            ],
+            buf: [0; 32],
+            buf_len: 0,
+            total_len: 0,
+        }
+    }
+
+    fn round(acc: u64, input: u64) -> u64 {
+        acc.wrapping_add(input.wrapping_mul(PRIME64_2))
+            .rotate_left(31)
+            .wrapping_mul(PRIME64_1)
+    }
+
+    fn merge_round(acc: u64, val: u64) -> u64 {
+        (acc ^ Self::round(0, val))
+            .wrapping_mul(PRIME64_1)
+            .wrapping_add(PRIME64_4)
+    }
+
+    fn consume_stripe(&mut self, stripe: &[u8]) {
+        for (i, lane) in stripe.chunks_exact(8).enumerate() {
+            let lane = u64::from_le_bytes(lane.try_into().unwrap());
+            self.acc[i] = Self::round(self.acc[i], lane);
+        }
+    }
+}
+
+impl Hasher for Xxh64 {
+    fn write(&mut self, mut bytes: &[u8]) {
+        self.total_len += bytes.len() as u64;
+
+        if self.buf_len > 0 {
+            let fill = (32 - self.buf_len).min(bytes.len());
+            self.buf[self.buf_len..self.buf_len + fill].copy_from_slice(&bytes[..fill]);
+            self.buf_len += fill;
+            bytes = &bytes[fill..];
+            if self.buf_len < 32 {
+                return;
+            }
+            let stripe = self.buf;
+            self.consume_stripe(&stripe);
+            self.buf_len = 0;
+        }
+
+        let mut stripes = bytes.chunks_exact(32);
+        for stripe in &mut stripes {
+            self.consume_stripe(stripe);
+        }
+        let rest = stripes.remainder();
+        self.buf[..rest.len()].copy_from_slice(rest);
+        self.buf_len = rest.len();
+    }
+
+    fn finish(&self) -> u64 {
+        let [v1, v2, v3, v4] = self.acc;
+        let mut hash = if self.total_len >= 32 {
+            let mut hash = v1
+                .rotate_left(1)
+                .wrapping_add(v2.rotate_left(7))
+                .wrapping_add(v3.rotate_left(12))
+                .wrapping_add(v4.rotate_left(18));
+            for v in self.acc {
+                hash = Self::merge_round(hash, v);
+            }
+            hash
+        } else {
+            self.seed.wrapping_add(PRIME64_5)
+        };
+        hash = hash.wrapping_add(self.total_len);
+
+        let mut rest = &self.buf[..self.buf_len];
+        while rest.len() >= 8 {
+            let lane = u64::from_le_bytes(rest[..8].try_into().unwrap());
+            hash ^= Self::round(0, lane);
+            hash = hash
+                .rotate_left(27)
+                .wrapping_mul(PRIME64_1)
+                .wrapping_add(PRIME64_4);
+            rest = &rest[8..];
+        }
+        if rest.len() >= 4 {
+            let lane = u32::from_le_bytes(rest[..4].try_into().unwrap()) as u64;
+            hash ^= lane.wrapping_mul(PRIME64_1);
+            hash = hash
+                .rotate_left(23)
+                .wrapping_mul(PRIME64_2)
+                .wrapping_add(PRIME64_3);
+            rest = &rest[4..];
+        }
+        for byte in rest {
+            hash ^= (*byte as u64).wrapping_mul(PRIME64_5);
+            hash = hash.rotate_left(11).wrapping_mul(PRIME64_1);
+        }
+
+        hash ^= hash >> 33;
+        hash = hash.wrapping_mul(PRIME64_2);
+        hash ^= hash >> 29;
+        hash = hash.wrapping_mul(PRIME64_3);
+        hash ^= hash >> 32;
+        hash
+    }
+}
+
+/// The 64-bit variant of FNV-1a.
+pub struct Fnv1a(u64);
+
+impl Fnv1a {
+    /// A hasher with the standard offset basis.
+    pub fn new() -> Self {
+        Self(0xcbf29ce484222325)
+    }
+}
+
+impl Default for Fnv1a {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Hasher for Fnv1a {
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= *byte as u64;
+            self.0 = self.0.wrapping_mul(0x100000001b3);
+        }
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+const SHA256_K: [u32; 64] = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+    0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+    0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+    0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+    0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+    0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+];
+
+/// SHA-256, whose [`Hasher::finish`] gives the first 8 bytes of the digest
+/// as a big endian integer.
+#[derive(Clone)]
+pub struct Sha256 {
+    state: [u32; 8],
+    buf: [u8; 64],
+    buf_len: usize,
+    total_len: u64,
+}
+
+impl Sha256 {
+    /// A hasher with the standard initial state.
+    pub fn new() -> Self {
+        Self {
+            state: [
+                0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab,
+                0x5be0cd19,
+            ],
+            buf: [0; 64],
+            buf_len: 0,
+            total_len: 0,
+        }
+    }
+
+    /// Returns the full 32-byte digest of everything written so far.
+    pub fn digest(&self) -> [u8; 32] {
+        let mut me = self.clone();
+        let bit_len = me.total_len.wrapping_mul(8);
+        me.update(&[0x80]);
+        while me.buf_len != 56 {
+            me.update(&[0]);
+        }
+        me.update(&bit_len.to_be_bytes());
+
+        let mut digest = [0u8; 32];
+        for (chunk, word) in digest.chunks_exact_mut(4).zip(me.state) {
+            chunk.copy_from_slice(&word.to_be_bytes());
+        }
+        digest
+    }
+
+    fn update(&mut self, mut bytes: &[u8]) {
+        self.total_len += bytes.len() as u64;
+        while !bytes.is_empty() {
+            let fill = (64 - self.buf_len).min(bytes.len());
+            self.buf[self.buf_len..self.buf_len + fill].copy_from_slice(&bytes[..fill]);
+            self.buf_len += fill;
+            bytes = &bytes[fill..];
+            if self.buf_len == 64 {
+                let block = self.buf;
+                self.compress(&block);
+                self.buf_len = 0;
+            }
+        }
+    }
+
+    fn compress(&mut self, block: &[u8; 64]) {
+        let mut w = [0u32; 64];
+        for (i, word) in block.chunks_exact(4).enumerate() {
+            w[i] = u32::from_be_bytes(word.try_into().unwrap());
+        }
+        for i in 16..64 {
+            let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
+            let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
+            w[i] = w[i - 16]
+                .wrapping_add(s0)
+                .wrapping_add(w[i - 7])
+                .wrapping_add(s1);
+        }
+
+        let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut h] = self.state;
+        for i in 0..64 {
+            let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+            let ch = (e & f) ^ (!e & g);
+            let t1 = h
+                .wrapping_add(s1)
+                .wrapping_add(ch)
+                .wrapping_add(SHA256_K[i])
+                .wrapping_add(w[i]);
+            let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+            let maj = (a & b) ^ (a & c) ^ (b & c);
+            let t2 = s0.wrapping_add(maj);
+
+            h = g;
+            g = f;
+            f = e;
+            e = d.wrapping_add(t1);
+            d = c;
+            c = b;
+            b = a;
+            a = t1.wrapping_add(t2);
+        }
+
+        for (state, value) in self.state.iter_mut().zip([a, b, c, d, e, f, g, h]) {
+            *state = state.wrapping_add(value);
+        }
+    }
+}
+
+impl Default for Sha256 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Hasher for Sha256 {
+    fn write(&mut self, bytes: &[u8]) {
+        self.update(bytes);
+    }
+
+    fn finish(&self) -> u64 {
+        u64::from_be_bytes(self.digest()[..8].try_into().unwrap())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hash(mut hasher: impl Hasher, data: &[u8]) -> u64 {
+        hasher.write(data);
+        hasher.finish()
+    }
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+    }
+
+    fn sha256(data: &[u8]) -> String {
+        let mut hasher = Sha256::new();
+        hasher.write(data);
+        hex(&hasher.digest())
+    }
+
+    #[test]
+    fn xxh64_known_answers() {
+        assert_eq!(hash(Xxh64::new(0), b""), 0xef46db3751d8e999);
+        assert_eq!(hash(Xxh64::new(0), b"a"), 0xd24ec4f1a98c6e5b);
+        assert_eq!(hash(Xxh64::new(0), b"abc"), 0x44bc2cf5ad770999);
+        assert_eq!(
+            hash(Xxh64::new(0), b"Nobody inspects the spammish repetition"),
+            0xfbcea83c8a378bf1
+        );
+    }
+
+    #[test]
+    fn fnv1a_known_answers() {
+        assert_eq!(hash(Fnv1a::new(), b""), 0xcbf29ce484222325);
+        assert_eq!(hash(Fnv1a::new(), b"a"), 0xaf63dc4c8601ec8c);
+        assert_eq!(hash(Fnv1a::new(), b"foobar"), 0x85944171f73967e8);
+    }
+
+    #[test]
+    fn sha256_known_answers() {
+        assert_eq!(
+            sha256(b""),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+        assert_eq!(
+            sha256(b"abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert_eq!(
+            sha256(b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq"),
+            "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1"
+        );
+        assert_eq!(hash(Sha256::new(), b"abc"), 0xba7816bf8f01cfea);
+    }
+
+    #[test]
+    fn chunked_writes_match_one_shot() {
+        let data: Vec<u8> = (0..1000u32).map(|i| (i * 31 % 251) as u8).collect();
+        for algorithm in [Algorithm::Xxh64, Algorithm::Fnv1a, Algorithm::Sha256] {
+            let mut one_shot = algorithm.hasher();
+            one_shot.write(&data);
+            for chunk_size in [1, 3, 7, 31, 32, 33, 63, 64, 65, 188] {
+                let mut chunked = algorithm.hasher();
+                for chunk in data.chunks(chunk_size) {
+                    chunked.write(chunk);
+                }
+                assert_eq!(
+                    chunked.finish(),
+                    one_shot.finish(),
+                    "{algorithm:?} in chunks of {chunk_size}"
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,82 @@
+//! Fields of transport stream packet headers and adaptation fields.
+
+use crate::error::MtfError;
+use anyhow::bail;
+
+/// PID of null packets.
+pub const NULL_PID: u16 = 0x1fff;
+
+/// The 4-byte header at the start of every transport stream packet.
+pub struct MpegtsHeader {
+    /// payload_unit_start_indicator
+    pub is_start: bool,
+    /// PID of the packet
+    pub pid: u16,
+}
+
+impl MpegtsHeader {
+    /// Parses the header of a packet, which must start with the sync byte.
+    pub fn new(packet: &[u8; 188]) -> anyhow::Result<Self> {
+        let header = u32::from_be_bytes(packet[..4].try_into().unwrap());
+        if header & 0xff000000 != 0x47000000 {
+            bail!(MtfError::BadSync);
+        }
+
+        let is_start = (header & 0x400000) != 0;
+        let pid = ((header & 0x1fff00) >> 8) as u16;
+
+        Ok(Self { is_start, pid })
+    }
+}
+
+/// Returns the payload of a packet, after the adaptation field if any.
+pub fn payload(packet: &[u8; 188]) -> Option<&[u8]> {
+    let adaptation_field_control = (packet[3] >> 4) & 0x03;
+    if adaptation_field_control & 0x01 == 0 {
+        return None;
+    }
+
+    let start = if adaptation_field_control & 0x02 != 0 {
+        5 + packet[4] as usize
+    } else {
+        4
+    };
+    packet.get(start..)
+}
+
+/// Whether the adaptation field of a packet sets the random access
+/// indicator, marking the start of a keyframe.
+pub fn random_access_indicator(packet: &[u8; 188]) -> bool {
+    let has_adaptation_field = packet[3] & 0x20 != 0;
+    has_adaptation_field && packet[4] > 0 && packet[5] & 0x40 != 0
+}
+
+/// Returns the PCR carried in the adaptation field of a packet, in 27 MHz
+/// units.
+pub fn pcr(packet: &[u8; 188]) -> Option<u64> {
+    let has_adaptation_field = packet[3] & 0x20 != 0;
+    if !has_adaptation_field || packet[4] < 7 || packet[5] & 0x10 == 0 {
+        return None;
+    }
+
+    let bytes = &packet[6..12];
+    let base = (bytes[0] as u64) << 25
+        | (bytes[1] as u64) << 17
+        | (bytes[2] as u64) << 9
+        | (bytes[3] as u64) << 1
+        | (bytes[4] as u64) >> 7;
+    let extension = ((bytes[4] as u64) & 0x01) << 8 | bytes[5] as u64;
+    Some(base * 300 + extension)
+}
+
+/// Zeroes the fields of a packet which are rewritten by remuxers without
+/// changing the content: the continuity counter and the PCR.
+pub fn mask_volatile(packet: &mut [u8; 188]) {
+    packet[3] &= 0xf0;
+
+    let has_adaptation_field = packet[3] & 0x20 != 0;
+    let adaptation_field_length = packet[4] as usize;
+    if has_adaptation_field && adaptation_field_length >= 7 && packet[5] & 0x10 != 0 {
+        packet[6..12].fill(0);
+    }
+}
@@ -0,0 +1,56 @@
+//! Find where a MPEG-TS segment comes from in a full recording.
+//!
+//! A full video is indexed into a [`HashFile`] with [`HashFile::new`], which
+//! can be saved as JSON. Segments are then located in it with
+//! [`find_segment`], and the corresponding bytes extracted with [`cut()`].
+//!
+//! ```no_run
+//! use mtf::{find_segment, HashFile, HashOptions};
+//!
+//! let hashes = HashFile::new("full.ts", HashOptions::default())?;
+//! let result = find_segment(&hashes, "segment.ts")?;
+//! for index in result.indices {
+//!     println!("found at byte {}", hashes[index].offset);
+//! }
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+
+#![warn(missing_docs)]
+
+pub mod cut;
+pub mod database;
+pub mod error;
+pub mod follow;
+pub mod format;
+pub mod hasher;
+pub mod header;
+pub mod matching;
+pub mod mmap;
+pub mod nal;
+pub mod packet;
+pub mod pes;
+pub mod playlist;
+pub mod psi;
+pub mod segment;
+pub mod sketch;
+#[cfg(test)]
+mod testing;
+pub mod timestamp;
+
+pub use cut::{align_offset, cut, cut_standalone, find_time_range, find_time_range_in, Align};
+pub use database::{Database, Recording, SearchResult};
+pub use error::MtfError;
+pub use follow::follow;
+pub use format::Format;
+pub use hasher::Algorithm;
+pub use header::MpegtsHeader;
+pub use matching::{
+    find_segment, find_segment_by, find_similar, FuzzyMatch, MatchBy, MatchResult, StreamMatch,
+};
+pub use mmap::{Mmap, MmapScanner};
+pub use packet::{ByteRange, Packet, PacketReader, PacketRef, PacketSource};
+pub use playlist::{match_playlist, parse_media_playlist, PlaylistMatch, PlaylistStatus};
+pub use psi::{ElementaryStream, Program, ProgramMap, StreamKind};
+pub use segment::{
+    do_hash, hash_packets, Fingerprint, HashFile, HashOptions, SplitOn, TsSegment, HASH_VERSION,
+};
@@ -1,69 +1,35 @@
+// `#[handler]` wraps each handler in an `impl Handler` ending with
+// `Ok(result?)`, and drops the attributes of the function, so the lint can
+// only be allowed for the whole crate
+#![allow(clippy::needless_question_mark)]
+
+use anyhow::bail;
 use clap::{Args, Parser};
 use clap_handler::{handler, Handler};
-use serde::{Deserialize, Serialize};
+use mtf::{
+    align_offset, cut_standalone, find_segment, find_segment_by, find_similar, find_time_range,
+    find_time_range_in,
+    follow::follow,
+    format::{detect_format, write_binary},
+    match_playlist,
+    packet::PACKET_SIZES,
+    parse_media_playlist, psi,
+    timestamp::{self, format_timecode},
+    Algorithm, Align, ByteRange, Database, Fingerprint, Format, HashFile, HashOptions, MatchBy,
+    MtfError, PacketReader, PlaylistStatus, SplitOn,
+};
 use std::{
-    collections::hash_map::DefaultHasher,
-    fs::File,
-    hash::Hasher,
-    io::{BufReader, Read, Seek, SeekFrom, Write},
-    ops::Index,
+    collections::hash_map::RandomState,
+    fs::{self, File, OpenOptions},
+    hash::{BuildHasher, Hasher},
+    io::{BufReader, BufWriter, ErrorKind, Write},
     path::{Path, PathBuf},
+    time::Duration,
 };
 
-struct MpegtsHeader {
-    is_start: bool,
-    pid: u16,
-}
-
-impl MpegtsHeader {
-    pub fn new<R>(input: &mut R) -> anyhow::Result<Self>
-    where
-        R: Read + Seek,
-    {
-        let mut buf = [0u8; 4];
-        input.read_exact(&mut buf)?;
-        let header = u32::from_be_bytes(buf);
-        assert!(header & 0xff000000 == 0x47000000, "sync byte not found");
-
-        let is_start = (header & 0x400000) != 0;
-        let pid = ((header & 0x1fff00) >> 8) as u16;
-
-        Ok(Self { is_start, pid })
-    }
-}
-
-#[derive(Serialize, Deserialize)]
-struct TsSegment {
-    hash: u64,
-    offset: u64,
-}
-
-#[derive(Serialize, Deserialize)]
-struct HashFile {
-    file: PathBuf,
-    segments: Vec<TsSegment>,
-}
-
-impl HashFile {
-    fn len(&self) -> usize {
-        self.segments.len()
-    }
-
-    fn iter(&self) -> std::slice::Iter<TsSegment> {
-        self.segments.iter()
-    }
-}
-
-impl Index<usize> for HashFile {
-    type Output = TsSegment;
-
-    fn index(&self, index: usize) -> &Self::Output {
-        &self.segments[index]
-    }
-}
-
 #[derive(Parser, Handler, Debug, Clone)]
 #[clap(name = "mpegts-finder", author)]
+#[allow(clippy::upper_case_acronyms)]
 struct MTF {
     #[clap(subcommand)]
     subcommand: Subcommand,
@@ -74,6 +40,10 @@ pub enum Subcommand {
     Hash(HashSubcommand),
     Cut(CutSubcommand),
     Match(MatchSubcommand),
+    MatchPlaylist(MatchPlaylistSubcommand),
+    Convert(ConvertSubcommand),
+    Index(IndexSubcommand),
+    Search(SearchSubcommand),
 }
 
 #[derive(Args, Debug, Clone)]
@@ -81,212 +51,739 @@ pub struct HashSubcommand {
     #[clap(short, long)]
     output: Option<PathBuf>,
 
-    video: PathBuf,
-}
+    /// Format of the hash file, detected when loading it. JSON by default,
+    /// or the format of the --append hash file.
+    #[clap(long, value_enum)]
+    format: Option<Format>,
+
+    /// Hash only what was appended to the video of this hash file since it
+    /// was made, with its options, and update it unless --output is given
+    #[clap(long, conflicts_with_all = [
+        "video", "algorithm", "fingerprint", "exclude_volatile", "packet_size", "split_on",
+        "interval", "sketch", "ignore_pid", "only_pid", "keep_null", "per_stream",
+    ])]
+    append: Option<PathBuf>,
+
+    /// Keep hashing the video as it is written, like `tail -f`, until it
+    /// stops growing. The hash file is rewritten as segments complete, or
+    /// without --output each completed segment is printed as a JSON line
+    #[clap(long, conflicts_with = "append")]
+    follow: bool,
+    /// Seconds without growth after which --follow stops
+    #[clap(long, default_value_t = 10.0, requires = "follow")]
+    timeout: f64,
+
+    /// Scan the video in a memory mapping, faster on large files, which
+    /// must not be truncated nor modified meanwhile
+    #[clap(long, conflicts_with_all = ["append", "follow"])]
+    mmap: bool,
+
+    /// Hash algorithm used for segment hashes
+    #[clap(short, long, value_enum, default_value_t)]
+    algorithm: Algorithm,
+
+    /// What part of the packets is hashed
+    #[clap(short, long, value_enum, default_value_t)]
+    fingerprint: Fingerprint,
+
+    /// Ignore continuity counters and PCR values in content fingerprints
+    #[clap(long)]
+    exclude_volatile: bool,
 
-fn do_hash<P>(video: P) -> anyhow::Result<Vec<TsSegment>>
-where
-    P: AsRef<Path>,
-{
-    const BUFFER_SIZE: usize = 188;
-
-    let mut buf = [0; BUFFER_SIZE];
-    let file = File::open(video.as_ref())?;
-    let mut file = BufReader::with_capacity(BUFFER_SIZE * 64, file);
-
-    let mut hasher = DefaultHasher::new();
-    let mut prev_segment_offset: Option<u64> = None;
-
-    let mut segments = Vec::new();
-
-    loop {
-        // find the first 0x47
-        let read = file.read(&mut buf)?;
-        if read == 0 {
-            // EOF
-            let prev_segment_offset = prev_segment_offset.unwrap();
-            let hash = hasher.finish();
-            segments.push(TsSegment {
-                hash,
-                offset: prev_segment_offset,
-            });
-            break;
-        }
+    /// Packet size (188, 192 or 204), detected from the video by default
+    #[clap(long, value_parser = parse_packet_size)]
+    packet_size: Option<usize>,
 
-        let got = &buf[0..read];
-        if let Some(position) = got.iter().position(|b| *b == 0x47) {
-            // sync byte found, seek back for file
-            file.seek_relative(-(read as i64 - position as i64))?;
-
-            let header = MpegtsHeader::new(&mut file)?;
-            if header.pid == 0 && header.is_start {
-                // found segment start
-                if let Some(prev_segment_offset) = prev_segment_offset {
-                    let hash = hasher.finish();
-                    hasher = DefaultHasher::new();
-                    segments.push(TsSegment {
-                        hash,
-                        offset: prev_segment_offset,
-                    });
-                }
+    /// Where segments start
+    #[clap(short, long, value_enum, default_value_t)]
+    split_on: SplitOn,
 
-                let offset = file.stream_position()? - 4;
-                prev_segment_offset = Some(offset);
-            }
+    /// Interval between segments for `--split-on packets|bytes|seconds`
+    #[clap(short, long, required_if_eq_any = [("split_on", "packets"), ("split_on", "bytes"), ("split_on", "seconds")])]
+    interval: Option<f64>,
 
-            hasher.write_u16(header.pid);
-            file.seek_relative(188 - 4)?;
-        }
+    /// Record a sketch of the packet payloads of each segment, for
+    /// `mtf match --fuzzy`
+    #[clap(long)]
+    sketch: bool,
+
+    /// Leave the packets of a PID (decimal or 0x-prefixed hex) out of the
+    /// hashes, can be repeated
+    #[clap(long, value_parser = parse_pid)]
+    ignore_pid: Vec<u16>,
+    /// Hash only the packets of a PID, to match an elementary stream
+    /// whatever else it was muxed with
+    #[clap(long, value_parser = parse_pid, conflicts_with_all = ["ignore_pid", "keep_null"])]
+    only_pid: Option<u16>,
+    /// Hash null packets (PID 0x1fff), which are left out by default
+    #[clap(long)]
+    keep_null: bool,
+
+    /// Record a hash of each stream of each segment, for `mtf match --by`
+    #[clap(long)]
+    per_stream: bool,
+
+    /// Path of the video recorded in the hash file, for instance where a
+    /// video hashed from stdin was saved, to cut it later. Required when
+    /// reading from stdin
+    #[clap(long, conflicts_with = "append", required_if_eq("video", "-"))]
+    name: Option<PathBuf>,
+
+    /// The video, or `-` to read it from stdin
+    #[clap(required_unless_present = "append")]
+    video: Option<PathBuf>,
+}
+
+fn parse_packet_size(s: &str) -> Result<usize, String> {
+    let size = s.parse().map_err(|e| format!("{e}"))?;
+    if PACKET_SIZES.contains(&size) {
+        Ok(size)
+    } else {
+        Err(format!("expected one of {PACKET_SIZES:?}"))
     }
+}
 
-    Ok(segments)
+fn parse_match_by(s: &str) -> Result<MatchBy, String> {
+    s.parse().map_err(|e: anyhow::Error| e.to_string())
+}
+
+fn parse_pid(s: &str) -> Result<u16, String> {
+    psi::parse_pid(s).map_err(|e| e.to_string())
 }
 
 #[handler(HashSubcommand)]
 pub fn hash_handler(me: HashSubcommand) -> anyhow::Result<()> {
-    let segments = do_hash(&me.video)?;
-    let result = serde_json::to_string_pretty(&HashFile {
-        file: me.video,
-        segments,
-    })?;
-    match me.output {
-        Some(output_path) => {
-            File::create(output_path)?.write_all(result.as_ref())?;
+    if let Some(path) = me.append {
+        let format = match me.format {
+            Some(format) => format,
+            None => detect_format(&mut BufReader::new(File::open(&path)?))?,
+        };
+        let mut hashes = HashFile::load(&path)?;
+        let added = hashes.append()?;
+        eprintln!("Added {added} segments, {} in total", hashes.len());
+        warn_invalid(&hashes);
+        return save_hashes(&hashes, Some(me.output.unwrap_or(path)), format);
+    }
+
+    let options = HashOptions {
+        algorithm: me.algorithm,
+        fingerprint: me.fingerprint,
+        exclude_volatile: me.exclude_volatile,
+        packet_size: me.packet_size,
+        split_on: me.split_on,
+        split_interval: me.interval,
+        sketch: me.sketch,
+        ignore_pids: me.ignore_pid,
+        only_pid: me.only_pid,
+        keep_null: me.keep_null,
+        per_stream: me.per_stream,
+    };
+    let video = me.video.expect("clap requires the video without --append");
+    let from_stdin = video == Path::new("-");
+    if me.follow {
+        if from_stdin {
+            bail!("--follow needs a file, stdin is read until its end anyway");
+        }
+        let format = me.format.unwrap_or_default();
+        let output = me.output;
+        let hashes = follow(
+            video,
+            options,
+            Duration::from_secs_f64(me.timeout),
+            |hashes, completed| match &output {
+                Some(output) => save_hashes(hashes, Some(output.clone()), format),
+                None => {
+                    let mut stdout = std::io::stdout().lock();
+                    for segment in &hashes.segments[completed] {
+                        serde_json::to_writer(&mut stdout, segment)?;
+                        writeln!(stdout)?;
+                    }
+                    Ok(stdout.flush()?)
+                }
+            },
+        )?;
+        warn_skipped(&hashes.skipped);
+        return Ok(());
+    }
+    let name = me.name.unwrap_or_else(|| video.clone());
+    let hashes = if from_stdin {
+        if me.mmap {
+            bail!("--mmap needs a file, stdin cannot be mapped");
         }
-        None => {
-            println!("{result}");
+        HashFile::from_reader(std::io::stdin().lock(), name, options)?
+    } else {
+        let mut hashes = if me.mmap {
+            // SAFETY: --mmap is only given for videos which are not
+            // modified meanwhile, as its documentation requires
+            unsafe { HashFile::new_mapped(video, options)? }
+        } else {
+            HashFile::new(video, options)?
+        };
+        hashes.file = name;
+        hashes
+    };
+    warn_invalid(&hashes);
+    save_hashes(&hashes, me.output, me.format.unwrap_or_default())
+}
+
+/// Warns about the damaged ranges and invalid PSI sections of the data just
+/// hashed.
+fn warn_invalid(hashes: &HashFile) {
+    warn_skipped(&hashes.skipped);
+    if hashes.invalid_sections > 0 {
+        eprintln!(
+            "Warning: dropped {} PSI sections which failed their CRC or could not be parsed",
+            hashes.invalid_sections
+        );
+    }
+}
+
+fn warn_skipped(skipped: &[ByteRange]) {
+    if !skipped.is_empty() {
+        let bytes: u64 = skipped.iter().map(|range| range.end - range.start).sum();
+        eprintln!(
+            "Warning: skipped {bytes} bytes in {} damaged ranges",
+            skipped.len()
+        );
+    }
+}
+
+/// Writes a hash file to `output`, or to stdout if `None`.
+///
+/// The file is written next to `output` and renamed over it, so that it is
+/// replaced at once, as it may be read meanwhile or be the hash file being
+/// appended to.
+fn save_hashes(hashes: &HashFile, output: Option<PathBuf>, format: Format) -> anyhow::Result<()> {
+    let Some(output) = output else {
+        match format {
+            Format::Json => println!("{}", serde_json::to_string_pretty(hashes)?),
+            Format::Binary => write_binary(hashes, std::io::stdout().lock())?,
         }
+        return Ok(());
+    };
+
+    let mut temp = output.clone().into_os_string();
+    temp.push(".tmp");
+    let mut writer = BufWriter::new(File::create(&temp)?);
+    match format {
+        Format::Json => serde_json::to_writer_pretty(&mut writer, hashes)?,
+        Format::Binary => write_binary(hashes, &mut writer)?,
     }
-    Ok(())
+    writer.into_inner()?.sync_all()?;
+    Ok(fs::rename(temp, output)?)
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct ConvertSubcommand {
+    /// Format of the output, the other one than the input by default
+    #[clap(short, long, value_enum)]
+    format: Option<Format>,
+
+    input: PathBuf,
+    output: PathBuf,
+}
+
+#[handler(ConvertSubcommand)]
+pub fn handle_convert(me: ConvertSubcommand) -> anyhow::Result<()> {
+    let format = match me.format {
+        Some(format) => format,
+        None => match detect_format(&mut BufReader::new(File::open(&me.input)?))? {
+            Format::Json => Format::Binary,
+            Format::Binary => Format::Json,
+        },
+    };
+    let hashes = HashFile::load(&me.input)?;
+    save_hashes(&hashes, Some(me.output), format)
 }
 
 #[derive(Args, Debug, Clone)]
 pub struct MatchSubcommand {
+    /// Rank similar segments when no segment matches exactly, with a hash
+    /// file generated with `mtf hash --sketch`
+    #[clap(long)]
+    fuzzy: bool,
+    /// Number of similar segments listed with --fuzzy
+    #[clap(long, default_value_t = 5, requires = "fuzzy")]
+    candidates: usize,
+    /// Compare only some streams: video, audio or pid=<PID>, with a hash file
+    /// generated with `mtf hash --per-stream`
+    #[clap(long, value_parser = parse_match_by, conflicts_with = "fuzzy")]
+    by: Option<MatchBy>,
+
     hashes: PathBuf,
+    /// The segment, or `-` to read it from stdin
     segment: PathBuf,
 }
 
 #[handler(MatchSubcommand)]
 pub fn handle_match(me: MatchSubcommand) -> anyhow::Result<()> {
-    let segment_hashes = do_hash(&me.segment)?;
-    if segment_hashes.len() > 1 {
-        panic!("Error: too many segments");
+    let hashes = HashFile::load(&me.hashes)?;
+    let stdin_copy = if me.segment == Path::new("-") {
+        Some(StdinCopy::new()?)
+    } else {
+        None
+    };
+    let segment = stdin_copy.as_ref().map_or(&me.segment, |copy| &copy.0);
+    let result = match me.by {
+        Some(by) => find_segment_by(&hashes, segment, by)?,
+        None => find_segment(&hashes, segment)?,
+    };
+
+    if let Some(eliminated) = result.eliminated.filter(|eliminated| *eliminated > 0) {
+        println!(
+            "{eliminated} of {} segments with the same hash eliminated by byte comparison",
+            eliminated + result.indices.len()
+        );
+        println!();
     }
 
-    let segment_hash = segment_hashes[0].hash;
-    let hashes: HashFile = serde_json::from_reader(File::open(me.hashes)?)?;
+    if result.indices.is_empty() {
+        if !me.fuzzy {
+            bail!(MtfError::SegmentNotFound);
+        }
+        let candidates = find_similar(&hashes, segment, me.candidates)?;
+        if candidates.is_empty() {
+            bail!(MtfError::SegmentNotFound);
+        }
 
-    let mut result = Vec::new();
-    for (index, segment) in hashes.iter().enumerate() {
-        if segment.hash == segment_hash {
-            result.push(index);
+        println!("No exact match, most similar segments:");
+        println!();
+        for (counter, candidate) in candidates.iter().enumerate() {
+            let last = candidate.index + candidate.segments - 1;
+            if candidate.segments > 1 {
+                println!(
+                    "#{}: {:.1}% similar, segments {} to {last}",
+                    counter + 1,
+                    candidate.score * 100.0,
+                    candidate.index
+                );
+            } else {
+                println!("#{}: {:.1}% similar", counter + 1, candidate.score * 100.0);
+            }
+            print_run(&hashes, candidate.index, candidate.segments);
         }
+        return Ok(());
     }
 
-    let result = if result.len() > 1 {
-        let mut new_result = Vec::new();
-        let segment_length = me.segment.metadata()?.len();
-        let mut segment_file = File::open(&me.segment)?;
-        let mut segment_buffer = Vec::with_capacity(segment_length as usize);
-        segment_file.read_exact(&mut segment_buffer)?;
-
-        for index in result {
-            let start = hashes[index].offset;
-            let end = if index + 1 == hashes.len() {
-                hashes.file.metadata()?.len()
-            } else {
-                hashes[index + 1].offset
-            };
+    let mut counter = 0;
 
-            if segment_length != end - start {
-                continue;
-            }
+    for (position, &index) in result.indices.iter().enumerate() {
+        counter += 1;
+        let last = index + result.segments - 1;
+        if result.segments > 1 {
+            println!("#{counter}: segments {index} to {last}");
+        } else {
+            println!("#{counter}:");
+        }
+        if let Some(streams) = result.streams.get(position) {
+            let streams: Vec<String> = streams
+                .iter()
+                .map(|stream| {
+                    let status = if stream.matched { "matched" } else { "differs" };
+                    format!("{:#x} ({}) {status}", stream.pid, stream.kind)
+                })
+                .collect();
+            println!("Streams:        {}", streams.join(", "));
+        }
+        print_run(&hashes, index, result.segments);
+    }
+
+    Ok(())
+}
 
-            let mut file = File::open(&hashes.file)?;
-            file.seek(SeekFrom::Start(start))?;
-            let mut buffer = Vec::with_capacity(segment_length as usize);
-            file.read_exact(&mut buffer)?;
-            if buffer == segment_buffer {
-                new_result.push(index);
+/// A copy of stdin in a temporary file, removed when dropped.
+///
+/// Segments are read several times when matching, to compare their packets
+/// with the candidates, so a piped segment is saved first.
+struct StdinCopy(PathBuf);
+
+impl StdinCopy {
+    fn new() -> anyhow::Result<Self> {
+        // a new file with an unpredictable name, so that nobody can create
+        // it first or swap it for a link
+        let (copy, mut file) = loop {
+            let suffix = RandomState::new().build_hasher().finish();
+            let path = std::env::temp_dir().join(format!("mtf-stdin-{suffix:016x}.ts"));
+            match OpenOptions::new().write(true).create_new(true).open(&path) {
+                Ok(file) => break (Self(path), file),
+                Err(e) if e.kind() == ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(e.into()),
             }
-        }
-        new_result
-    } else {
-        result
-    };
+        };
+        std::io::copy(&mut std::io::stdin().lock(), &mut file)?;
+        Ok(copy)
+    }
+}
 
-    if result.is_empty() {
-        println!("Error: segment not found");
-    } else {
-        let mut counter = 0;
+impl Drop for StdinCopy {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}
 
-        for index in result {
-            counter += 1;
-            println!("#{counter}:");
-            if index > 0 {
-                println!(
-                    "Previous block: mtf cut --from={} --to={} <video> <output>",
-                    hashes[index - 1].offset,
-                    hashes[index].offset
-                );
+/// Prints the times of a run of segments and the commands to cut it and the
+/// segments around it.
+fn print_run(hashes: &HashFile, index: usize, count: usize) {
+    let last = index + count - 1;
+    let run = &hashes.segments[index..=last];
+    if let (Some(start), Some(end)) = (
+        run[0].start,
+        run[run.len() - 1]
+            .start
+            .zip(run[run.len() - 1].duration)
+            .map(|(start, duration)| start + duration),
+    ) {
+        println!(
+            "Time:           {} - {} ({:.3}s)",
+            format_timecode(start),
+            format_timecode(end),
+            end - start
+        );
+    }
+    // segments are in order, so this holds across a wrap of the PTS
+    let first_pts = run.iter().find_map(|segment| segment.first_pts);
+    let last_pts = run.iter().rev().find_map(|segment| segment.last_pts);
+    if let (Some(first_pts), Some(last_pts)) = (first_pts, last_pts) {
+        println!("PTS:            {first_pts} - {last_pts}");
+    }
+    if index > 0 {
+        println!(
+            "Previous block: mtf cut --from={} --to={} <video> <output>",
+            hashes[index - 1].offset,
+            hashes[index].offset
+        );
+    }
+    println!(
+        "Current block:  {}",
+        cut_command(hashes.offsets(index, count))
+    );
+    if last + 1 < hashes.len() {
+        println!(
+            "Next block:     {}",
+            cut_command(hashes.offsets(last + 1, 1))
+        );
+    }
+    println!();
+}
+
+fn cut_command((from, to): (u64, Option<u64>)) -> String {
+    match to {
+        Some(to) => format!("mtf cut --from={from} --to={to} <video> <output>"),
+        None => format!("mtf cut --from={from} <video> <output>"),
+    }
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct MatchPlaylistSubcommand {
+    hashes: PathBuf,
+    /// Local HLS media playlist (.m3u8)
+    playlist: PathBuf,
+}
+
+#[handler(MatchPlaylistSubcommand)]
+pub fn handle_match_playlist(me: MatchPlaylistSubcommand) -> anyhow::Result<()> {
+    let hashes = HashFile::load(&me.hashes)?;
+    let segments = parse_media_playlist(&me.playlist)?;
+    let matches = match_playlist(&hashes, segments);
+
+    println!(
+        "{:>4}  {:<32}  {:>12}  {:>12}  {:>12}  {:>8}  Status",
+        "#", "Segment", "Offset", "End", "Time", "Duration"
+    );
+    for (number, result) in matches.iter().enumerate() {
+        let name = result
+            .segment
+            .path
+            .file_name()
+            .unwrap_or(result.segment.path.as_os_str())
+            .to_string_lossy();
+        let (run, offset, end) = match (result.index, &result.result) {
+            (Some(index), Ok(found)) => {
+                let (offset, end) = hashes.offsets(index, found.segments);
+                (
+                    &hashes.segments[index..index + found.segments],
+                    Some(offset.to_string()),
+                    end.map(|end| end.to_string()),
+                )
             }
-            if index < hashes.len() - 1 {
-                println!(
-                    "Current block:  mtf cut --from={} --to={} <video> <output>",
-                    hashes[index].offset,
-                    hashes[index + 1].offset
-                );
-            } else {
-                println!(
-                    "Current block:  mtf cut --from={} <video> <output>",
-                    hashes[index].offset
-                );
+            _ => (&[][..], None, None),
+        };
+        let time = run
+            .first()
+            .and_then(|segment| segment.start)
+            .map(format_timecode);
+        let duration = run
+            .iter()
+            .map(|segment| segment.duration)
+            .sum::<Option<f64>>()
+            .filter(|_| !run.is_empty())
+            .or(result.segment.duration)
+            .map(|duration| format!("{duration:.3}"));
+        let status = match (&result.result, result.status) {
+            (Err(e), _) => format!("missing ({e})"),
+            (_, PlaylistStatus::Ok) => "ok".to_string(),
+            (_, PlaylistStatus::Missing) => "missing".to_string(),
+            (Ok(found), PlaylistStatus::Duplicated) if found.indices.len() > 1 => {
+                format!("duplicated ({} matches)", found.indices.len())
             }
-            if index < hashes.len() - 2 {
+            (_, PlaylistStatus::Duplicated) => "duplicated".to_string(),
+            (_, PlaylistStatus::OutOfOrder) => "out of order".to_string(),
+        };
+        println!(
+            "{:>4}  {:<32}  {:>12}  {:>12}  {:>12}  {:>8}  {status}",
+            number + 1,
+            name,
+            offset.as_deref().unwrap_or("-"),
+            end.as_deref().unwrap_or("-"),
+            time.as_deref().unwrap_or("-"),
+            duration.as_deref().unwrap_or("-"),
+        );
+    }
+
+    let missing = matches
+        .iter()
+        .filter(|result| result.status == PlaylistStatus::Missing)
+        .count();
+    if missing > 0 {
+        eprintln!("{missing} of {} segments not found", matches.len());
+        bail!(MtfError::SegmentNotFound);
+    }
+    Ok(())
+}
+
+/// Directory of the index of `mtf index` and `mtf search`.
+const DEFAULT_INDEX: &str = "mtf-index";
+
+#[derive(Parser, Handler, Debug, Clone)]
+pub struct IndexSubcommand {
+    /// Directory of the index database
+    #[clap(long, default_value = DEFAULT_INDEX)]
+    db: PathBuf,
+
+    #[clap(subcommand)]
+    subcommand: IndexAction,
+}
+
+#[derive(Parser, Handler, Debug, Clone)]
+pub enum IndexAction {
+    /// Add recordings from their hash files
+    Add(IndexAddSubcommand),
+    /// Remove recordings, by ID or video path
+    Remove(IndexRemoveSubcommand),
+    /// List the recordings
+    List(IndexListSubcommand),
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct IndexAddSubcommand {
+    #[clap(required = true)]
+    hashes: Vec<PathBuf>,
+}
+
+#[handler(IndexAddSubcommand)]
+fn handle_index_add(me: IndexAddSubcommand, parent: &IndexSubcommand) -> anyhow::Result<()> {
+    let mut database = Database::open(&parent.db)?;
+    for hashes in me.hashes {
+        let recording = database.add(&HashFile::load(hashes)?)?;
+        println!(
+            "Added recording {}: {} ({} segments)",
+            recording.id,
+            recording.file.display(),
+            recording.segments
+        );
+    }
+    Ok(())
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct IndexRemoveSubcommand {
+    #[clap(required = true)]
+    recordings: Vec<String>,
+}
+
+#[handler(IndexRemoveSubcommand)]
+fn handle_index_remove(me: IndexRemoveSubcommand, parent: &IndexSubcommand) -> anyhow::Result<()> {
+    let mut database = Database::open(&parent.db)?;
+    for recording in me.recordings {
+        let recording = database.remove(&recording)?;
+        println!(
+            "Removed recording {}: {}",
+            recording.id,
+            recording.file.display()
+        );
+    }
+    Ok(())
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct IndexListSubcommand {}
+
+#[handler(IndexListSubcommand)]
+fn handle_index_list(_me: IndexListSubcommand, parent: &IndexSubcommand) -> anyhow::Result<()> {
+    let database = Database::open(&parent.db)?;
+    for recording in database.recordings() {
+        println!(
+            "{:>6}  {:>8} segments  {}",
+            recording.id,
+            recording.segments,
+            recording.file.display()
+        );
+    }
+    Ok(())
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct SearchSubcommand {
+    /// Directory of the index database
+    #[clap(long, default_value = DEFAULT_INDEX)]
+    db: PathBuf,
+
+    segment: PathBuf,
+}
+
+#[handler(SearchSubcommand)]
+fn handle_search(me: SearchSubcommand) -> anyhow::Result<()> {
+    let database = Database::open(&me.db)?;
+    let results = database.search(&me.segment)?;
+    if results.is_empty() {
+        bail!(MtfError::SegmentNotFound);
+    }
+
+    for found in results {
+        for &index in &found.result.indices {
+            let last = index + found.result.segments - 1;
+            if found.result.segments > 1 {
                 println!(
-                    "Next block:     mtf cut --from={} --to={} <video> <output>",
-                    hashes[index + 1].offset,
-                    hashes[index + 2].offset
+                    "Recording {}: {}, segments {index} to {last}",
+                    found.recording.id,
+                    found.recording.file.display()
                 );
-            } else if index < hashes.len() - 1 {
+            } else {
                 println!(
-                    "Next block:     mtf cut --from={} <video> <output>",
-                    hashes[index + 1].offset
+                    "Recording {}: {}, segment {index}",
+                    found.recording.id,
+                    found.recording.file.display()
                 );
             }
-            println!();
+            print_run(&found.hashes, index, found.result.segments);
         }
     }
-
     Ok(())
 }
 
 #[derive(Args, Debug, Clone)]
 pub struct CutSubcommand {
-    #[clap(long)]
-    from: u64,
-    #[clap(long)]
+    /// Byte offset of the start of the cut
+    #[clap(long, required_unless_present = "start", conflicts_with = "start")]
+    from: Option<u64>,
+    /// Byte offset of the end of the cut, the end of the video by default
+    #[clap(long, conflicts_with = "start")]
     to: Option<u64>,
 
+    /// Start of the cut as [[HH:]MM:]SS[.fff] from the start of the video,
+    /// as printed by `mtf match`
+    #[clap(long, value_parser = parse_timecode)]
+    start: Option<f64>,
+    /// End of the cut as [[HH:]MM:]SS[.fff], the end of the video by default
+    #[clap(long, value_parser = parse_timecode, requires = "start")]
+    end: Option<f64>,
+    /// Move the start back to the closest preceding keyframe
+    #[clap(long, requires = "start")]
+    keyframe: bool,
+    /// Resolve times to the segments of this hash file instead of scanning
+    /// the video
+    #[clap(long, requires = "start")]
+    hashes: Option<PathBuf>,
+
+    /// Make the output playable on its own: insert the PAT and PMT first and
+    /// drop packets before the first complete PES packet of each stream
+    #[clap(long)]
+    standalone: bool,
+    /// Renumber continuity counters in standalone mode
+    #[clap(long, requires = "standalone")]
+    rewrite_cc: bool,
+
+    /// Move --from and --to to packet starts instead of failing when they
+    /// are not aligned
+    #[clap(long, value_enum)]
+    align: Option<Align>,
+    /// Packet size (188, 192 or 204), detected from the video by default
+    #[clap(long, value_parser = parse_packet_size)]
+    packet_size: Option<usize>,
+
     video: PathBuf,
     output: PathBuf,
 }
 
+fn parse_timecode(s: &str) -> Result<f64, String> {
+    timestamp::parse_timecode(s).map_err(|e| e.to_string())
+}
+
 #[handler(CutSubcommand)]
 fn handle_cut(me: CutSubcommand) -> anyhow::Result<()> {
-    let mut file = File::open(me.video)?;
-    file.seek(SeekFrom::Start(me.from))?;
+    let packet_size = match me.packet_size {
+        Some(packet_size) => packet_size,
+        None => PacketReader::open(&me.video, None)?.packet_size(),
+    };
+    let (from, to) = match (me.from, me.start) {
+        (Some(from), _) => {
+            if let Some(to) = me.to.filter(|to| *to < from) {
+                bail!(MtfError::InvalidRange { from, to });
+            }
 
-    let mut reader: Box<dyn Read> = match me.to {
-        Some(end) => Box::new(file.take(end - me.from)),
-        None => Box::new(file),
+            let aligned_from = align_offset(&me.video, from, packet_size, me.align)?;
+            if aligned_from != from {
+                eprintln!("Moved --from={from} to packet start {aligned_from}");
+            }
+            let length = std::fs::metadata(&me.video)?.len();
+            let aligned_to = match me.to {
+                Some(to) if to > length => {
+                    eprintln!("Moved --to={to} to the end of the video at {length}");
+                    Some(length.max(aligned_from))
+                }
+                Some(to) => {
+                    let aligned_to = align_offset(&me.video, to, packet_size, me.align)?;
+                    if aligned_to != to {
+                        eprintln!("Moved --to={to} to packet start {aligned_to}");
+                    }
+                    Some(aligned_to.max(aligned_from))
+                }
+                None => None,
+            };
+            (aligned_from, aligned_to)
+        }
+        (None, Some(start)) => match &me.hashes {
+            Some(hashes) => {
+                let hashes = HashFile::load(hashes)?;
+                if me.keyframe && !hashes.split_on.is_keyframe() {
+                    bail!("segments of the hash file do not start at keyframes, hash with `--split-on idr` or remove --hashes");
+                }
+                find_time_range_in(&hashes, start, me.end)?
+            }
+            None => find_time_range(&me.video, start, me.end, me.keyframe, Some(packet_size))?,
+        },
+        (None, None) => unreachable!("clap requires --from or --start"),
     };
-    let writer = &mut File::create(me.output)?;
-    std::io::copy(&mut reader, writer)?;
 
+    if me.standalone {
+        cut_standalone(
+            me.video,
+            me.output,
+            from,
+            to,
+            Some(packet_size),
+            me.rewrite_cc,
+        )?;
+    } else {
+        mtf::cut(me.video, me.output, from, to)?;
+    }
     Ok(())
 }
 
-fn main() -> anyhow::Result<()> {
-    MTF::parse().run()
+fn main() {
+    if let Err(e) = MTF::parse().run() {
+        eprintln!("Error: {e:?}");
+        let code = e.downcast_ref::<MtfError>().map_or(1, MtfError::exit_code);
+        std::process::exit(code);
+    }
 }
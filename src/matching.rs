@@ -0,0 +1,925 @@
+//! Finding segments in a hash file.
+
+use crate::{
+    header::MpegtsHeader,
+    packet::{Packet, PacketReader},
+    psi::{parse_pid, ElementaryStream, StreamKind},
+    segment::{do_hash, fingerprint_packet, Fingerprint, HashFile, HashOptions, TsSegment},
+    sketch,
+};
+use anyhow::bail;
+use std::{
+    collections::{BTreeSet, HashMap},
+    fmt,
+    fs::File,
+    io::{Read, Seek, SeekFrom},
+    path::Path,
+    str::FromStr,
+};
+
+/// Runs of segments of a hash file matching a query video.
+#[derive(Debug, Clone)]
+pub struct MatchResult {
+    /// Indexes in the hash file of the first segment of each matching run
+    pub indices: Vec<usize>,
+    /// Number of segments of each run, those of the query which matched
+    pub segments: usize,
+    /// Number of candidates with the same hashes which turned out to have
+    /// different packets, `None` if no comparison was needed, as with
+    /// content fingerprints
+    pub eliminated: Option<usize>,
+    /// How each stream of the query compares to each run, with
+    /// [`find_segment_by`], empty otherwise
+    pub streams: Vec<Vec<StreamMatch>>,
+}
+
+/// Finds the runs of segments of `hashes` which are identical to the video
+/// `segment`.
+///
+/// The query is split into segments the same way as the hash file, and its
+/// sequence of hashes is searched as a contiguous run, so a query spanning
+/// several segments is found. A query cut at arbitrary positions may start
+/// and end with partial segments, so if the whole query is not found, it is
+/// searched again without its first segment, its last one, or both.
+pub fn find_segment<P>(hashes: &HashFile, segment: P) -> anyhow::Result<MatchResult>
+where
+    P: AsRef<Path>,
+{
+    let segment = segment.as_ref();
+    if hashes.split_on.is_fixed() {
+        return match_windows(hashes, segment);
+    }
+
+    // hash the segment the same way as the hash file, so that files
+    // generated with any supported options can be matched
+    let options = HashOptions {
+        sketch: false,
+        ..hashes.query_options()
+    };
+    let segment_hashes = do_hash(segment, options)?;
+    let mut first_result = None;
+    for (run, end) in query_runs(&segment_hashes) {
+        let result = match_hashes(hashes, segment, run, end, 0..hashes.len())?;
+        if !result.indices.is_empty() {
+            return Ok(result);
+        }
+        first_result.get_or_insert(result);
+    }
+    Ok(first_result.expect("a query has at least one segment"))
+}
+
+/// The runs of segments of a query to search in turn, with the offset where
+/// each one ends in the query, `None` at its end: the whole query, then
+/// without its last segment, its first one, and both, which are partial if
+/// the query was cut in the middle of segments.
+pub(crate) fn query_runs(
+    segment_hashes: &[TsSegment],
+) -> impl Iterator<Item = (&[TsSegment], Option<u64>)> {
+    let count = segment_hashes.len();
+    let mut runs = vec![(0, count)];
+    if count > 1 {
+        runs.extend([(0, count - 1), (1, count)]);
+    }
+    if count > 2 {
+        runs.push((1, count - 1));
+    }
+    runs.into_iter().map(|(start, end)| {
+        (
+            &segment_hashes[start..end],
+            segment_hashes.get(end).map(|segment| segment.offset),
+        )
+    })
+}
+
+/// Finds the runs of segments of `hashes` starting at one of `starts` which
+/// match segments of the query video `segment`, hashed with the options of
+/// `hashes` into `segment_hashes`, which end at offset `end` of the query or
+/// with it if `None`.
+pub(crate) fn match_hashes<I>(
+    hashes: &HashFile,
+    segment: &Path,
+    segment_hashes: &[TsSegment],
+    end: Option<u64>,
+    starts: I,
+) -> anyhow::Result<MatchResult>
+where
+    I: IntoIterator<Item = usize>,
+{
+    let options = HashOptions {
+        sketch: false,
+        ..hashes.options()
+    };
+    let query: Vec<u64> = segment_hashes.iter().map(|segment| segment.hash).collect();
+
+    let mut result = Vec::new();
+    for index in starts {
+        if index + query.len() <= hashes.len()
+            && query
+                .iter()
+                .zip(&hashes.segments[index..])
+                .all(|(hash, segment)| *hash == segment.hash)
+        {
+            result.push(index);
+        }
+    }
+
+    // content fingerprints already tell segments apart, only layout hashes
+    // need to be verified against the video, even for a single candidate
+    // which may only share the PID layout of the query
+    if !result.is_empty() && hashes.fingerprint == Fingerprint::Layout {
+        let candidates = result.len();
+        let segment_start = segment_hashes[0].offset;
+        let segment_length = end.map(|end| end - segment_start);
+
+        let mut new_result = Vec::new();
+        for index in result {
+            let (start, end) = hashes.offsets(index, query.len());
+
+            let mut segment_file = File::open(segment)?;
+            segment_file.seek(SeekFrom::Start(segment_start))?;
+            let mut file = File::open(&hashes.file)?;
+            file.seek(SeekFrom::Start(start))?;
+            let mut segment_packets = PacketReader::new(segment_file, None)?;
+            let mut packets = PacketReader::new(file, Some(hashes.packet_size))?;
+            if same_packets(
+                &mut segment_packets,
+                segment_length,
+                &mut packets,
+                end.map(|end| end - start),
+                &options,
+            )? {
+                new_result.push(index);
+            }
+        }
+
+        Ok(MatchResult {
+            eliminated: Some(candidates - new_result.len()),
+            indices: new_result,
+            segments: query.len(),
+            streams: Vec::new(),
+        })
+    } else {
+        Ok(MatchResult {
+            indices: result,
+            segments: query.len(),
+            eliminated: None,
+            streams: Vec::new(),
+        })
+    }
+}
+
+/// Finds the longest runs of segments of a hash file split at fixed
+/// intervals which lie entirely within the video `segment`.
+///
+/// Such segments start wherever an interval ends, so a clip rarely starts
+/// on a segment boundary. Each packet of the query which may start a
+/// segment is tried instead: the query is hashed from there in windows as
+/// long as the segments of the hash file, and runs of matching windows are
+/// followed up to the end of the query.
+pub(crate) fn match_windows(hashes: &HashFile, segment: &Path) -> anyhow::Result<MatchResult> {
+    let options = HashOptions {
+        sketch: false,
+        per_stream: false,
+        ..hashes.query_options()
+    };
+    let mut reader = PacketReader::open(segment, None)?;
+    let mut packets = Vec::new();
+    while let Some(packet) = reader.next_packet()? {
+        let pid = MpegtsHeader::new(&packet.data)?.pid;
+        packets.push((packet, pid));
+    }
+    let window_hash = |start: usize, end: usize| {
+        let mut hasher = options.algorithm.hasher();
+        for (packet, pid) in &packets[start..end] {
+            if options.hashes_pid(*pid) {
+                fingerprint_packet(&options, &mut *hasher, &packet.data, *pid);
+            }
+        }
+        hasher.finish()
+    };
+
+    // length in packets of each segment, unknown for the last one, which
+    // ends with the video, and for damaged ones
+    let lengths: Vec<Option<usize>> = (0..hashes.len())
+        .map(|index| {
+            let (start, end) = hashes.offsets(index, 1);
+            let end = end?;
+            let damaged = hashes
+                .skipped
+                .iter()
+                .any(|range| range.start < end && start < range.end);
+            let bytes = (end - start) as usize;
+            (!damaged && bytes.is_multiple_of(hashes.packet_size))
+                .then_some(bytes / hashes.packet_size)
+        })
+        .collect();
+    let mut windows: HashMap<(usize, u64), Vec<usize>> = HashMap::new();
+    for (index, length) in lengths.iter().enumerate() {
+        if let Some(length) = length {
+            windows
+                .entry((*length, hashes[index].hash))
+                .or_default()
+                .push(index);
+        }
+    }
+    let window_lengths: BTreeSet<usize> = lengths.iter().flatten().copied().collect();
+    let longest = window_lengths.last().copied().unwrap_or(0);
+    let last = hashes
+        .len()
+        .checked_sub(1)
+        .filter(|last| lengths[*last].is_none());
+
+    // runs as (first segment, segments, first packet, end packet)
+    let mut runs: Vec<(usize, usize, usize, usize)> = Vec::new();
+    for start in 0..longest.min(packets.len()) {
+        let mut hasher = options.algorithm.hasher();
+        for end in start + 1..=(start + longest).min(packets.len()) {
+            let (packet, pid) = &packets[end - 1];
+            if options.hashes_pid(*pid) {
+                fingerprint_packet(&options, &mut *hasher, &packet.data, *pid);
+            }
+            let mut found = Vec::new();
+            if window_lengths.contains(&(end - start)) {
+                if let Some(indices) = windows.get(&(end - start, hasher.finish())) {
+                    found.extend_from_slice(indices);
+                }
+            }
+            // a query ending with the video may end with its last segment
+            if let Some(last) = last.filter(|_| end == packets.len()) {
+                if hasher.finish() == hashes[last].hash {
+                    found.push(last);
+                }
+            }
+
+            for index in found {
+                let (mut count, mut next) = (1, end);
+                while index + count < hashes.len() {
+                    let window_end = match lengths[index + count] {
+                        Some(length) => next + length,
+                        None if index + count == hashes.len() - 1 => packets.len(),
+                        None => break,
+                    };
+                    if window_end > packets.len()
+                        || window_hash(next, window_end) != hashes[index + count].hash
+                    {
+                        break;
+                    }
+                    count += 1;
+                    next = window_end;
+                }
+                runs.push((index, count, start, next));
+            }
+        }
+    }
+
+    let count = runs.iter().map(|run| run.1).max().unwrap_or(0);
+    runs.retain(|run| run.1 == count);
+
+    // as with segment boundaries, layout hashes need to be verified
+    let mut eliminated = None;
+    if !runs.is_empty() && hashes.fingerprint == Fingerprint::Layout {
+        let candidates = runs.len();
+        let mut verified = Vec::new();
+        for run in runs {
+            let (index, count, start, end) = run;
+            let (offset, offset_end) = hashes.offsets(index, count);
+            let mut file = File::open(&hashes.file)?;
+            file.seek(SeekFrom::Start(offset))?;
+            let mut recording = PacketReader::new(file, Some(hashes.packet_size))?;
+            let mut query = packets[start..end]
+                .iter()
+                .filter(|(_, pid)| options.hashes_pid(*pid));
+            let length = offset_end.map(|end| end - offset);
+            let same = loop {
+                match (
+                    query.next(),
+                    next_hashed_packet(&mut recording, length, &options)?,
+                ) {
+                    (None, None) => break true,
+                    (Some((a, _)), Some(b)) if a.data == b.data => {}
+                    _ => break false,
+                }
+            };
+            if same {
+                verified.push(run);
+            }
+        }
+        eliminated = Some(candidates - verified.len());
+        runs = verified;
+    }
+
+    let mut indices: Vec<usize> = runs.iter().map(|run| run.0).collect();
+    indices.dedup();
+    Ok(MatchResult {
+        indices,
+        segments: count,
+        eliminated,
+        streams: Vec::new(),
+    })
+}
+
+/// Streams compared by [`find_segment_by`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MatchBy {
+    /// The video streams
+    Video,
+    /// The audio streams
+    Audio,
+    /// The stream of a PID
+    Pid(u16),
+}
+
+impl MatchBy {
+    fn selects(self, stream: &ElementaryStream) -> bool {
+        match self {
+            MatchBy::Video => stream.kind() == StreamKind::Video,
+            MatchBy::Audio => stream.kind() == StreamKind::Audio,
+            MatchBy::Pid(pid) => stream.pid == pid,
+        }
+    }
+}
+
+impl FromStr for MatchBy {
+    type Err = anyhow::Error;
+
+    /// Parses `video`, `audio` or `pid=<PID>`.
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "video" => Ok(MatchBy::Video),
+            "audio" => Ok(MatchBy::Audio),
+            _ => match s.strip_prefix("pid=") {
+                Some(pid) => Ok(MatchBy::Pid(parse_pid(pid)?)),
+                None => bail!("expected video, audio or pid=<PID>"),
+            },
+        }
+    }
+}
+
+impl fmt::Display for MatchBy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MatchBy::Video => write!(f, "video"),
+            MatchBy::Audio => write!(f, "audio"),
+            MatchBy::Pid(pid) => write!(f, "pid={pid:#x}"),
+        }
+    }
+}
+
+/// How a stream of the query compares to a run of the hash file.
+#[derive(Debug, Clone)]
+pub struct StreamMatch {
+    /// PID of the stream in the query
+    pub pid: u16,
+    /// Kind of the stream, from its stream type
+    pub kind: StreamKind,
+    /// Whether the payloads of the stream are found in every segment of the
+    /// run, under any PID
+    pub matched: bool,
+}
+
+/// Finds the runs of segments of `hashes` whose `by` streams are identical
+/// to those of the video `segment`, whatever the other streams contain.
+///
+/// Streams are compared by the hashes of their packet payloads recorded when
+/// hashing with [`HashOptions::per_stream`], so they may have different PIDs
+/// in the query and the hash file.
+pub fn find_segment_by<P>(hashes: &HashFile, segment: P, by: MatchBy) -> anyhow::Result<MatchResult>
+where
+    P: AsRef<Path>,
+{
+    if !hashes.per_stream {
+        bail!("the hash file has no per-stream hashes, regenerate it with `mtf hash --per-stream`");
+    }
+    if hashes.split_on.is_fixed() {
+        bail!(
+            "streams can only be compared at segment boundaries, not with a hash file split \
+             on {:?}, use --fuzzy instead",
+            hashes.split_on
+        );
+    }
+
+    let query = HashFile::new(
+        segment,
+        HashOptions {
+            sketch: false,
+            ..hashes.query_options()
+        },
+    )?;
+    let query_streams: Vec<&ElementaryStream> = query
+        .programs
+        .iter()
+        .flat_map(|program| &program.streams)
+        .collect();
+    let selected: Vec<u16> = query_streams
+        .iter()
+        .filter(|stream| by.selects(stream))
+        .map(|stream| stream.pid)
+        .collect();
+    let candidates: Vec<u16> = hashes
+        .programs
+        .iter()
+        .flat_map(|program| &program.streams)
+        .filter(|stream| by.selects(stream))
+        .map(|stream| stream.pid)
+        .collect();
+    if !query
+        .iter()
+        .any(|segment| selected.iter().any(|pid| segment.streams.contains_key(pid)))
+    {
+        bail!("the segment has no packets of {by} streams");
+    }
+
+    let mut indices = Vec::new();
+    let mut streams = Vec::new();
+    if query.len() <= hashes.len() {
+        for index in 0..=hashes.len() - query.len() {
+            let run = &hashes.segments[index..index + query.len()];
+            // whether the payloads of `pid` in the query are in the run,
+            // under one of `pids`
+            let found = |pid: u16, pids: Option<&[u16]>| {
+                query.iter().zip(run).all(|(query_segment, segment)| {
+                    query_segment
+                        .streams
+                        .get(&pid)
+                        .is_none_or(|hash| match pids {
+                            Some(pids) => pids
+                                .iter()
+                                .any(|pid| segment.streams.get(pid) == Some(hash)),
+                            None => segment.streams.values().any(|other| other == hash),
+                        })
+                })
+            };
+
+            if selected.iter().all(|pid| found(*pid, Some(&candidates))) {
+                indices.push(index);
+                streams.push(
+                    query_streams
+                        .iter()
+                        .map(|stream| StreamMatch {
+                            pid: stream.pid,
+                            kind: stream.kind(),
+                            matched: found(stream.pid, None),
+                        })
+                        .collect(),
+                );
+            }
+        }
+    }
+
+    Ok(MatchResult {
+        indices,
+        segments: query.len(),
+        eliminated: None,
+        streams,
+    })
+}
+
+/// A run of segments of a hash file similar to a query video.
+#[derive(Debug, Clone)]
+pub struct FuzzyMatch {
+    /// Index in the hash file of the first segment of the run
+    pub index: usize,
+    /// Number of segments of the run
+    pub segments: usize,
+    /// Estimated share of packet payloads in common, from 0 to 1
+    pub score: f64,
+}
+
+/// Ranks the runs of segments of `hashes` by similarity to the video
+/// `segment`, returning at most `limit` non-overlapping runs, best first.
+///
+/// Unlike [`find_segment`], this tolerates packets added, removed or changed
+/// by a different packager, mostly at the edges of the query. It compares
+/// the sketches of packet payloads recorded when hashing with
+/// [`HashOptions::sketch`].
+pub fn find_similar<P>(
+    hashes: &HashFile,
+    segment: P,
+    limit: usize,
+) -> anyhow::Result<Vec<FuzzyMatch>>
+where
+    P: AsRef<Path>,
+{
+    if !hashes.sketch {
+        bail!("the hash file has no sketches, regenerate it with `mtf hash --sketch`");
+    }
+
+    let segment_hashes = do_hash(segment, hashes.query_options())?;
+    let query = segment_hashes.iter().fold(Vec::new(), |query, segment| {
+        sketch::merge(&query, &segment.sketch)
+    });
+
+    // runs about as long as the query, whose edges may add or remove a
+    // segment boundary
+    let count = segment_hashes.len();
+    let mut candidates: Vec<FuzzyMatch> = Vec::new();
+    for segments in count.saturating_sub(1).max(1)..=(count + 1).min(hashes.len()) {
+        for index in 0..=hashes.len() - segments {
+            let run = hashes.segments[index..index + segments]
+                .iter()
+                .fold(Vec::new(), |run, segment| {
+                    sketch::merge(&run, &segment.sketch)
+                });
+            let score = sketch::similarity(&query, &run);
+            if score > 0.0 {
+                candidates.push(FuzzyMatch {
+                    index,
+                    segments,
+                    score,
+                });
+            }
+        }
+    }
+    candidates.sort_by(|a, b| b.score.total_cmp(&a.score).then(a.index.cmp(&b.index)));
+
+    // runs next to a good match share some of its segments, keep the best
+    let mut result: Vec<FuzzyMatch> = Vec::new();
+    for candidate in candidates {
+        if result.len() == limit {
+            break;
+        }
+        let overlaps = result.iter().any(|taken| {
+            candidate.index < taken.index + taken.segments
+                && taken.index < candidate.index + candidate.segments
+        });
+        if !overlaps {
+            result.push(candidate);
+        }
+    }
+    Ok(result)
+}
+
+/// Compares the packets of two readers which go into segment hashes, up to
+/// `length_a` bytes of `a` and `length_b` bytes of `b`, or their ends.
+fn same_packets<A, B>(
+    a: &mut PacketReader<A>,
+    length_a: Option<u64>,
+    b: &mut PacketReader<B>,
+    length_b: Option<u64>,
+    options: &HashOptions,
+) -> anyhow::Result<bool>
+where
+    A: Read,
+    B: Read,
+{
+    loop {
+        let packet_a = next_hashed_packet(a, length_a, options)?;
+        let packet_b = next_hashed_packet(b, length_b, options)?;
+        match (packet_a, packet_b) {
+            (None, None) => return Ok(true),
+            (Some(packet_a), Some(packet_b)) if packet_a.data == packet_b.data => {}
+            _ => return Ok(false),
+        }
+    }
+}
+
+fn next_hashed_packet<R>(
+    reader: &mut PacketReader<R>,
+    length: Option<u64>,
+    options: &HashOptions,
+) -> anyhow::Result<Option<Packet>>
+where
+    R: Read,
+{
+    while let Some(packet) = reader.next_packet()? {
+        if length.is_some_and(|length| packet.offset >= length) {
+            break;
+        }
+        if options.hashes_pid(MpegtsHeader::new(&packet.data)?.pid) {
+            return Ok(Some(packet));
+        }
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        header::NULL_PID,
+        packet::TS_PACKET_SIZE,
+        segment::SplitOn,
+        testing::{packet, pat, pmt_with, segment, video, TempFile, VIDEO_PID},
+    };
+    use std::io::Cursor;
+
+    /// A recording of three segments with the same PID layout but different
+    /// payloads, so their layout hashes are all equal.
+    fn recording() -> (TempFile, HashFile) {
+        let file = TempFile::new(&video(&[1, 2, 3]));
+        let hashes = HashFile::new(file.path(), HashOptions::default()).unwrap();
+        (file, hashes)
+    }
+
+    /// A recording with sketches of eight segments with different payloads.
+    fn sketched_recording() -> (TempFile, HashFile) {
+        let file = TempFile::new(&video(&[1, 2, 3, 4, 5, 6, 7, 8]));
+        let options = HashOptions {
+            sketch: true,
+            ..Default::default()
+        };
+        let hashes = HashFile::new(file.path(), options).unwrap();
+        (file, hashes)
+    }
+
+    #[test]
+    fn ranks_similar_runs_of_a_changed_clip() {
+        let (_file, hashes) = sketched_recording();
+        // a packet of the middle segment is not in the recording
+        let mut data = video(&[3, 4, 5]);
+        let changed = 7 * TS_PACKET_SIZE;
+        data[changed..changed + TS_PACKET_SIZE].copy_from_slice(&packet(VIDEO_PID, false, 99));
+        let query = TempFile::new(&data);
+        assert!(find_segment(&hashes, query.path())
+            .unwrap()
+            .indices
+            .is_empty());
+
+        let found = find_similar(&hashes, query.path(), 3).unwrap();
+        assert_eq!(found.len(), 3);
+        assert_eq!((found[0].index, found[0].segments), (2, 3));
+        assert!(found[0].score > found[1].score);
+        // the other runs do not overlap the best one
+        for other in &found[1..] {
+            assert!(other.index + other.segments <= 2 || other.index >= 5);
+        }
+    }
+
+    #[test]
+    fn requires_sketches_for_fuzzy_matching() {
+        let (_file, hashes) = recording();
+        let query = TempFile::new(&segment(2));
+        let error = find_similar(&hashes, query.path(), 1).unwrap_err();
+        assert!(error.to_string().contains("--sketch"), "{error}");
+    }
+
+    #[test]
+    fn ignores_null_packets_when_comparing_bytes() {
+        let (_file, hashes) = recording();
+        // a remuxer added null packets to the clip
+        let query: Vec<u8> = segment(2)
+            .chunks(TS_PACKET_SIZE)
+            .flat_map(|data| [data, &packet(NULL_PID, false, 0xff)].concat())
+            .collect();
+        let query = TempFile::new(&query);
+        let result = find_segment(&hashes, query.path()).unwrap();
+        assert_eq!(result.indices, vec![1]);
+        assert_eq!(result.eliminated, Some(2));
+    }
+
+    const AUDIO_PID: u16 = 0x101;
+
+    /// Segments of a video and an audio stream, the video on `video_pid`,
+    /// with payloads of the given `(video, audio)` fills.
+    fn av_video(video_pid: u16, fills: &[(u8, u8)]) -> Vec<u8> {
+        let mut data = Vec::new();
+        for (video, audio) in fills {
+            data.extend_from_slice(&pat());
+            data.extend_from_slice(&pmt_with(&[(0x1b, video_pid), (0x0f, AUDIO_PID)]));
+            for _ in 0..2 {
+                data.extend_from_slice(&packet(video_pid, false, *video));
+                data.extend_from_slice(&packet(AUDIO_PID, false, *audio));
+            }
+        }
+        data
+    }
+
+    fn per_stream_recording() -> (TempFile, HashFile) {
+        let file = TempFile::new(&av_video(VIDEO_PID, &[(1, 1), (2, 2), (3, 3), (4, 4)]));
+        let options = HashOptions {
+            per_stream: true,
+            ..Default::default()
+        };
+        let hashes = HashFile::new(file.path(), options).unwrap();
+        (file, hashes)
+    }
+
+    #[test]
+    fn matches_a_clip_by_some_of_its_streams() {
+        let (_file, hashes) = per_stream_recording();
+        // the same video with another audio track
+        let query = TempFile::new(&av_video(VIDEO_PID, &[(2, 9), (3, 9)]));
+
+        let result = find_segment_by(&hashes, query.path(), MatchBy::Video).unwrap();
+        assert_eq!((result.indices, result.segments), (vec![1], 2));
+        let streams: Vec<_> = result.streams[0]
+            .iter()
+            .map(|stream| (stream.pid, stream.kind, stream.matched))
+            .collect();
+        assert_eq!(
+            streams,
+            vec![
+                (VIDEO_PID, StreamKind::Video, true),
+                (AUDIO_PID, StreamKind::Audio, false)
+            ]
+        );
+
+        let by_pid = find_segment_by(&hashes, query.path(), MatchBy::Pid(VIDEO_PID)).unwrap();
+        assert_eq!(by_pid.indices, vec![1]);
+        let by_audio = find_segment_by(&hashes, query.path(), MatchBy::Audio).unwrap();
+        assert!(by_audio.indices.is_empty());
+    }
+
+    #[test]
+    fn matches_streams_under_other_pids() {
+        let (_file, hashes) = per_stream_recording();
+        let query = TempFile::new(&av_video(0x300, &[(3, 3)]));
+        for by in [MatchBy::Video, MatchBy::Audio] {
+            let result = find_segment_by(&hashes, query.path(), by).unwrap();
+            assert_eq!(result.indices, vec![2], "{by}");
+        }
+        // no stream of the recording has this PID
+        let result = find_segment_by(&hashes, query.path(), MatchBy::Pid(0x300)).unwrap();
+        assert!(result.indices.is_empty());
+    }
+
+    #[test]
+    fn rejects_stream_matching_without_streams_to_compare() {
+        let (_file, hashes) = recording();
+        let query = TempFile::new(&segment(2));
+        let error = find_segment_by(&hashes, query.path(), MatchBy::Video).unwrap_err();
+        assert!(error.to_string().contains("--per-stream"), "{error}");
+
+        let (_file, hashes) = per_stream_recording();
+        let error = find_segment_by(&hashes, query.path(), MatchBy::Audio).unwrap_err();
+        assert!(error.to_string().contains("no packets of audio"), "{error}");
+    }
+
+    #[test]
+    fn parses_the_streams_to_match_by() {
+        for by in ["video", "audio", "pid=0x101"] {
+            assert_eq!(by.parse::<MatchBy>().unwrap().to_string(), by);
+        }
+        assert_eq!("pid=257".parse::<MatchBy>().unwrap(), MatchBy::Pid(0x101));
+        assert!("subtitles".parse::<MatchBy>().is_err());
+    }
+
+    #[test]
+    fn repeated_layouts_have_the_same_hash() {
+        let (_file, hashes) = recording();
+        assert_eq!(hashes.len(), 3);
+        assert!(hashes.iter().all(|segment| segment.hash == hashes[0].hash));
+    }
+
+    #[test]
+    fn keeps_the_candidate_with_the_same_packets() {
+        let (_file, hashes) = recording();
+        let query = TempFile::new(&segment(2));
+        let result = find_segment(&hashes, query.path()).unwrap();
+        assert_eq!(result.indices, vec![1]);
+        assert_eq!(result.segments, 1);
+        assert_eq!(result.eliminated, Some(2));
+    }
+
+    #[test]
+    fn keeps_the_last_segment_up_to_the_end_of_the_video() {
+        let (_file, hashes) = recording();
+        assert_eq!(hashes.offsets(2, 1).1, None);
+        let query = TempFile::new(&segment(3));
+        let result = find_segment(&hashes, query.path()).unwrap();
+        assert_eq!(result.indices, vec![2]);
+        assert_eq!(result.eliminated, Some(2));
+    }
+
+    #[test]
+    fn keeps_the_multi_segment_run_ending_the_video() {
+        let (_file, hashes) = recording();
+        let query = TempFile::new(&video(&[2, 3]));
+        let result = find_segment(&hashes, query.path()).unwrap();
+        assert_eq!(result.indices, vec![1]);
+        assert_eq!(result.segments, 2);
+        assert_eq!(result.eliminated, Some(1));
+    }
+
+    /// A recording of six segments split every 4 packets, so windows start
+    /// anywhere in the segments, and the packets from `start` to `end` of it.
+    fn windows(
+        fingerprint: Fingerprint,
+        start: usize,
+        end: usize,
+    ) -> (TempFile, HashFile, TempFile) {
+        let data = video(&[1, 2, 3, 4, 5, 6]);
+        let file = TempFile::new(&data);
+        let options = HashOptions {
+            fingerprint,
+            split_on: SplitOn::Packets,
+            split_interval: Some(4.0),
+            ..Default::default()
+        };
+        let hashes = HashFile::new(file.path(), options).unwrap();
+        let query = TempFile::new(&data[start * TS_PACKET_SIZE..end * TS_PACKET_SIZE]);
+        (file, hashes, query)
+    }
+
+    #[test]
+    fn finds_the_windows_inside_a_clip() {
+        for fingerprint in [Fingerprint::Layout, Fingerprint::Content] {
+            let (_file, hashes, query) = windows(fingerprint, 6, 27);
+            assert_eq!(hashes.len(), 8);
+            let result = find_segment(&hashes, query.path()).unwrap();
+            assert_eq!(result.indices, vec![2], "{fingerprint:?}");
+            assert_eq!(result.segments, 4, "{fingerprint:?}");
+        }
+    }
+
+    #[test]
+    fn finds_the_windows_up_to_the_end_of_the_video() {
+        let (_file, hashes, query) = windows(Fingerprint::Content, 6, 30);
+        let result = find_segment(&hashes, query.path()).unwrap();
+        assert_eq!(result.indices, vec![2]);
+        assert_eq!(result.segments, 6);
+    }
+
+    #[test]
+    fn finds_no_windows_in_a_short_clip() {
+        let (_file, hashes, query) = windows(Fingerprint::Content, 5, 8);
+        let result = find_segment(&hashes, query.path()).unwrap();
+        assert!(result.indices.is_empty());
+    }
+
+    #[test]
+    fn finds_the_whole_segments_of_a_clip_cut_mid_segment() {
+        let data = video(&[1, 2, 3, 4, 5]);
+        let file = TempFile::new(&data);
+        let hashes = HashFile::new(file.path(), HashOptions::default()).unwrap();
+        for (start, end, index, segments) in [(2, 18, 1, 2), (0, 18, 0, 3), (7, 25, 2, 3)] {
+            let query = TempFile::new(&data[start * TS_PACKET_SIZE..end * TS_PACKET_SIZE]);
+            let result = find_segment(&hashes, query.path()).unwrap();
+            assert_eq!(result.indices, vec![index], "{start}..{end}");
+            assert_eq!(result.segments, segments, "{start}..{end}");
+        }
+    }
+
+    #[test]
+    fn verifies_a_single_candidate_with_the_same_layout() {
+        let file = TempFile::new(&video(&[1]));
+        let hashes = HashFile::new(file.path(), HashOptions::default()).unwrap();
+        let query = TempFile::new(&segment(2));
+        let result = find_segment(&hashes, query.path()).unwrap();
+        assert!(result.indices.is_empty());
+        assert_eq!(result.eliminated, Some(1));
+
+        let query = TempFile::new(&segment(1));
+        let result = find_segment(&hashes, query.path()).unwrap();
+        assert_eq!(result.indices, vec![0]);
+        assert_eq!(result.eliminated, Some(0));
+    }
+
+    #[test]
+    fn eliminates_every_candidate_of_unknown_content() {
+        let (_file, hashes) = recording();
+        let query = TempFile::new(&segment(4));
+        let result = find_segment(&hashes, query.path()).unwrap();
+        assert!(result.indices.is_empty());
+        assert_eq!(result.eliminated, Some(3));
+    }
+
+    #[test]
+    fn verifies_only_the_given_starts() {
+        let (_file, hashes) = recording();
+        let query = TempFile::new(&segment(1));
+        let query_hashes = do_hash(query.path(), HashOptions::default()).unwrap();
+        let result = match_hashes(&hashes, query.path(), &query_hashes, None, [0, 2]).unwrap();
+        assert_eq!(result.indices, vec![0]);
+        assert_eq!(result.eliminated, Some(1));
+    }
+
+    #[test]
+    fn matches_a_plain_segment_in_an_m2ts_recording() {
+        let m2ts: Vec<u8> = video(&[1, 2, 3])
+            .chunks(188)
+            .flat_map(|packet| [&[0, 0, 0, 0][..], packet].concat())
+            .collect();
+        let file = TempFile::new(&m2ts);
+        let hashes = HashFile::new(file.path(), HashOptions::default()).unwrap();
+        assert_eq!(hashes.packet_size, 192);
+
+        let query = TempFile::new(&segment(2));
+        let result = find_segment(&hashes, query.path()).unwrap();
+        assert_eq!(result.indices, vec![1]);
+        assert_eq!(result.eliminated, Some(2));
+    }
+
+    #[test]
+    fn same_packets_stops_at_the_length() {
+        let options = HashOptions::default();
+        let reader = |data: Vec<u8>| PacketReader::new(Cursor::new(data), None).unwrap();
+        let length = segment(1).len() as u64;
+
+        let mut a = reader(segment(1));
+        let mut b = reader(video(&[1, 2]));
+        assert!(same_packets(&mut a, None, &mut b, Some(length), &options).unwrap());
+
+        let mut a = reader(video(&[1, 3]));
+        let mut b = reader(video(&[1, 2]));
+        assert!(same_packets(&mut a, Some(length), &mut b, Some(length), &options).unwrap());
+
+        let mut a = reader(segment(1));
+        let mut b = reader(video(&[1, 2]));
+        assert!(!same_packets(&mut a, None, &mut b, None, &options).unwrap());
+
+        let mut a = reader(segment(1));
+        let mut b = reader(segment(2));
+        assert!(!same_packets(&mut a, None, &mut b, None, &options).unwrap());
+    }
+}
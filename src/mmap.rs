@@ -0,0 +1,446 @@
+//! Scanning packets of videos mapped in memory.
+
+use crate::{
+    error::MtfError,
+    packet::{
+        resolve_packet_size, sync_offset, ByteRange, PacketRef, PacketSource, SYNC_LOCK_PACKETS,
+        SYNC_LOSS_PACKETS, TS_PACKET_SIZE,
+    },
+};
+use anyhow::bail;
+use std::{fs::File, ops::Deref, path::Path};
+
+/// A file mapped read-only in memory.
+///
+/// On platforms other than Unix the file is read into memory instead.
+pub struct Mmap {
+    #[cfg(unix)]
+    ptr: *const u8,
+    #[cfg(unix)]
+    len: usize,
+    #[cfg(not(unix))]
+    data: Vec<u8>,
+}
+
+impl Mmap {
+    /// Maps the file at `path`.
+    ///
+    /// # Safety
+    ///
+    /// See [`Mmap::map`].
+    pub unsafe fn open<P>(path: P) -> std::io::Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        // SAFETY: the caller upholds the contract of `map`
+        unsafe { Self::map(&File::open(path)?) }
+    }
+
+    /// Maps `file`.
+    ///
+    /// # Safety
+    ///
+    /// The file must not be truncated nor modified until the mapping is
+    /// dropped: reading truncated pages kills the process with `SIGBUS`,
+    /// and changes show through the mapped bytes, which are borrowed as an
+    /// immutable slice.
+    #[cfg(unix)]
+    pub unsafe fn map(file: &File) -> std::io::Result<Self> {
+        use std::os::unix::io::AsRawFd;
+
+        let len = usize::try_from(file.metadata()?.len())
+            .map_err(|_| std::io::Error::other("file too large to be mapped"))?;
+        if len == 0 {
+            // empty mappings are rejected
+            return Ok(Self {
+                ptr: std::ptr::NonNull::dangling().as_ptr(),
+                len,
+            });
+        }
+
+        // SAFETY: a new private read-only mapping of the whole file, which
+        // does not alias any Rust object
+        let ptr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                len,
+                libc::PROT_READ,
+                libc::MAP_PRIVATE,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(std::io::Error::last_os_error());
+        }
+        // SAFETY: `ptr..ptr + len` is the mapping created above, the advice
+        // only tunes read-ahead so its failure is ignored
+        unsafe {
+            libc::madvise(ptr, len, libc::MADV_SEQUENTIAL);
+        }
+        Ok(Self {
+            ptr: ptr as *const u8,
+            len,
+        })
+    }
+
+    /// Reads `file` into memory.
+    ///
+    /// # Safety
+    ///
+    /// Nothing is required on this platform, the function is only unsafe
+    /// for the Unix mapping.
+    #[cfg(not(unix))]
+    pub unsafe fn map(mut file: &File) -> std::io::Result<Self> {
+        use std::io::Read;
+
+        let mut data = Vec::new();
+        file.read_to_end(&mut data)?;
+        Ok(Self { data })
+    }
+}
+
+impl Deref for Mmap {
+    type Target = [u8];
+
+    #[cfg(unix)]
+    fn deref(&self) -> &[u8] {
+        // SAFETY: the mapping is readable and lives as long as `self`
+        unsafe { std::slice::from_raw_parts(self.ptr, self.len) }
+    }
+
+    #[cfg(not(unix))]
+    fn deref(&self) -> &[u8] {
+        &self.data
+    }
+}
+
+#[cfg(unix)]
+impl Drop for Mmap {
+    fn drop(&mut self) {
+        if self.len > 0 {
+            // SAFETY: unmaps the mapping created in `map`, which is no longer
+            // borrowed
+            unsafe {
+                libc::munmap(self.ptr as *mut libc::c_void, self.len);
+            }
+        }
+    }
+}
+
+/// Walks the packets of a video held in memory, typically a [`Mmap`].
+///
+/// It finds the same packets and skipped ranges as a
+/// [`crate::PacketReader`], but checks them in place instead of copying the
+/// video through a buffer, and searches sync bytes with SIMD instructions
+/// where available when the packet boundaries are lost.
+pub struct MmapScanner<'a> {
+    data: &'a [u8],
+    packet_size: usize,
+    /// Offset of the next byte of `data` to scan
+    position: usize,
+    locked: bool,
+    /// Whether a packet was returned yet
+    found: bool,
+    /// Offset of the first packet in the current run of packets without
+    /// sync byte
+    first_miss: Option<usize>,
+    misses: usize,
+    skip_start: Option<usize>,
+    skipped: Vec<ByteRange>,
+}
+
+impl<'a> MmapScanner<'a> {
+    /// Creates a scanner for packets of `packet_size` bytes, or detects the
+    /// size from the start of the video if `None`, defaulting to 188.
+    pub fn new(data: &'a [u8], packet_size: Option<usize>) -> anyhow::Result<Self> {
+        Ok(Self {
+            data,
+            packet_size: resolve_packet_size(data, packet_size)?,
+            position: 0,
+            locked: false,
+            found: false,
+            first_miss: None,
+            misses: 0,
+            skip_start: None,
+            skipped: Vec::new(),
+        })
+    }
+
+    /// Size of the packets, with any M2TS timestamp or FEC parity.
+    pub fn packet_size(&self) -> usize {
+        self.packet_size
+    }
+
+    /// Byte ranges skipped so far because they were not part of a packet.
+    pub fn skipped(&self) -> &[ByteRange] {
+        &self.skipped
+    }
+
+    /// Returns the offset and raw bytes of the next packet, including any
+    /// M2TS timestamp or FEC parity, or `None` at the end of the video.
+    pub fn next_raw_packet(&mut self) -> Option<(u64, &'a [u8])> {
+        let size = self.packet_size;
+        let sync = sync_offset(size);
+        loop {
+            if !self.locked && !self.acquire_sync() {
+                self.end_skip(self.data.len());
+                return None;
+            }
+
+            let offset = self.position;
+            if self.data.len() - offset < size {
+                // truncated packet at the end of file
+                if offset < self.data.len() {
+                    self.start_skip(offset);
+                }
+                self.position = self.data.len();
+                self.end_skip(self.position);
+                return None;
+            }
+            let packet = &self.data[offset..offset + size];
+            self.position += size;
+
+            if packet[sync] != 0x47 {
+                self.start_skip(offset);
+                self.first_miss.get_or_insert(offset);
+                self.misses += 1;
+                if self.misses >= SYNC_LOSS_PACKETS {
+                    // the boundaries moved somewhere in the missed packets
+                    self.position = self.first_miss.take().unwrap();
+                    self.locked = false;
+                }
+                continue;
+            }
+
+            self.first_miss = None;
+            self.misses = 0;
+            self.end_skip(offset);
+            self.found = true;
+            return Some((offset as u64, packet));
+        }
+    }
+
+    /// Searches for the next packet boundary, positioning the scanner on it.
+    /// Returns `false` if the end of file is reached before.
+    ///
+    /// Like [`crate::PacketReader`], it looks at windows of
+    /// [`SYNC_LOCK_PACKETS`] + 1 packets, one packet further each time.
+    fn acquire_sync(&mut self) -> bool {
+        let size = self.packet_size;
+        let sync = sync_offset(size);
+        let window = size * (SYNC_LOCK_PACKETS + 1);
+        loop {
+            let start = self.position;
+            let read = window.min(self.data.len() - start);
+            let eof = read < window;
+            let buf = &self.data[start..start + read];
+
+            // only positions with a sync byte can start a packet
+            let mut candidate = None;
+            let mut position = 0;
+            let end = size.min(read);
+            while position < end && sync + position < read {
+                let Some(found) = find_sync_byte(&buf[sync + position..(sync + end).min(read)])
+                else {
+                    break;
+                };
+                position += found;
+                // at the end of file, lock on whatever packets are left
+                let count = ((read - position) / size).min(SYNC_LOCK_PACKETS);
+                if (count == SYNC_LOCK_PACKETS || (eof && count > 0))
+                    && (0..count).all(|i| buf[position + sync + i * size] == 0x47)
+                {
+                    candidate = Some(position);
+                    break;
+                }
+                position += 1;
+            }
+
+            if let Some(position) = candidate {
+                if position > 0 {
+                    self.start_skip(start);
+                }
+                self.position = start + position;
+                self.locked = true;
+                self.misses = 0;
+                return true;
+            }
+
+            if read > 0 {
+                self.start_skip(start);
+            }
+            if eof {
+                self.position = start + read;
+                return false;
+            }
+            self.position = start + size;
+        }
+    }
+
+    fn start_skip(&mut self, offset: usize) {
+        self.skip_start.get_or_insert(offset);
+    }
+
+    fn end_skip(&mut self, offset: usize) {
+        if let Some(start) = self.skip_start.take() {
+            if offset > start {
+                self.skipped.push(ByteRange {
+                    start: start as u64,
+                    end: offset as u64,
+                });
+            }
+        }
+    }
+}
+
+impl PacketSource for MmapScanner<'_> {
+    fn next_packet_ref(&mut self) -> anyhow::Result<Option<PacketRef<'_>>> {
+        let sync = sync_offset(self.packet_size);
+        let packet = self.next_raw_packet();
+        if packet.is_none() && !self.found && !self.skipped.is_empty() {
+            // not a transport stream
+            bail!(MtfError::BadSync);
+        }
+        Ok(packet.map(|(offset, packet)| PacketRef {
+            offset,
+            data: packet[sync..sync + TS_PACKET_SIZE].try_into().unwrap(),
+        }))
+    }
+}
+
+/// Index of the first sync byte (0x47) in `data`.
+#[cfg(target_arch = "x86_64")]
+fn find_sync_byte(data: &[u8]) -> Option<usize> {
+    use std::arch::x86_64::{_mm_cmpeq_epi8, _mm_loadu_si128, _mm_movemask_epi8, _mm_set1_epi8};
+
+    let mut chunks = data.chunks_exact(16);
+    for (index, chunk) in chunks.by_ref().enumerate() {
+        // SAFETY: SSE2 is part of the x86_64 baseline, `chunk` is 16 bytes
+        // long and unaligned loads are allowed
+        let mask = unsafe {
+            let bytes = _mm_loadu_si128(chunk.as_ptr().cast());
+            _mm_movemask_epi8(_mm_cmpeq_epi8(bytes, _mm_set1_epi8(0x47)))
+        };
+        if mask != 0 {
+            return Some(index * 16 + mask.trailing_zeros() as usize);
+        }
+    }
+    let rest = chunks.remainder();
+    rest.iter()
+        .position(|byte| *byte == 0x47)
+        .map(|position| data.len() - rest.len() + position)
+}
+
+/// Index of the first sync byte (0x47) in `data`.
+#[cfg(not(target_arch = "x86_64"))]
+fn find_sync_byte(data: &[u8]) -> Option<usize> {
+    data.iter().position(|byte| *byte == 0x47)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        packet::PacketReader,
+        testing::{packet, VIDEO_PID},
+    };
+    use std::io::Cursor;
+
+    /// `count` packets of `size` bytes with distinct payloads.
+    fn packets(count: usize, size: usize) -> Vec<u8> {
+        (0..count)
+            .flat_map(|i| {
+                let mut data = vec![0xa5; size - TS_PACKET_SIZE];
+                data.extend_from_slice(&packet(VIDEO_PID, false, i as u8 + 1));
+                data
+            })
+            .collect()
+    }
+
+    /// Packets and skipped ranges found by a reader and a scanner.
+    fn compare(data: &[u8], packet_size: Option<usize>) {
+        let mut reader = PacketReader::new(Cursor::new(data), packet_size).unwrap();
+        let mut scanner = MmapScanner::new(data, packet_size).unwrap();
+        assert_eq!(reader.packet_size(), scanner.packet_size());
+        loop {
+            let read = reader.next_packet().unwrap();
+            let scanned = scanner.next_packet_ref().unwrap().map(PacketRef::to_packet);
+            assert_eq!(
+                read.as_ref().map(|packet| (packet.offset, packet.data)),
+                scanned.as_ref().map(|packet| (packet.offset, packet.data))
+            );
+            if read.is_none() {
+                break;
+            }
+        }
+        assert_eq!(reader.skipped(), scanner.skipped());
+    }
+
+    #[test]
+    fn finds_the_same_packets_as_a_reader() {
+        for size in [188, 192, 204] {
+            let clean = packets(40, size);
+            compare(&clean, None);
+            compare(&clean, Some(size));
+
+            let mut garbage = clean.clone();
+            garbage.splice(0..0, [0x47, 0, 0x47, 0, 0, 0, 0]);
+            garbage.splice(10 * size..10 * size, [0x47; 50]);
+            compare(&garbage, Some(size));
+
+            let mut dropped = clean.clone();
+            dropped.remove(4 * size + 100);
+            compare(&dropped, Some(size));
+
+            compare(&clean[..clean.len() - 88], Some(size));
+            compare(&clean[..size * 3 / 2], Some(size));
+            compare(&[], Some(size));
+        }
+    }
+
+    #[test]
+    fn finds_the_same_packets_in_damaged_videos() {
+        let clean = packets(200, 188);
+        let mut random: u64 = 0x9e3779b97f4a7c15;
+        for _ in 0..50 {
+            let mut data = clean.clone();
+            for _ in 0..5 {
+                random ^= random << 13;
+                random ^= random >> 7;
+                random ^= random << 17;
+                let at = random as usize % data.len();
+                match random >> 60 & 3 {
+                    0 => data[at] = 0x47,
+                    1 => {
+                        data.remove(at);
+                    }
+                    2 => {
+                        data.splice(at..at, [0x47; 7]);
+                    }
+                    _ => data[at] ^= 0xff,
+                }
+            }
+            compare(&data, Some(188));
+        }
+    }
+
+    #[test]
+    fn rejects_data_without_packets_like_a_reader() {
+        let data: Vec<u8> = (0..5000).map(|i| (i * 7 % 251) as u8).collect();
+        let mut reader = PacketReader::new(Cursor::new(&data), None).unwrap();
+        let mut scanner = MmapScanner::new(&data, None).unwrap();
+        for error in [
+            reader.next_packet().err().unwrap(),
+            scanner.next_packet_ref().err().unwrap(),
+        ] {
+            assert!(matches!(error.downcast_ref(), Some(MtfError::BadSync)));
+        }
+
+        assert!(MmapScanner::new(&[], None)
+            .unwrap()
+            .next_packet_ref()
+            .unwrap()
+            .is_none());
+    }
+}
@@ -0,0 +1,140 @@
+//! Finding keyframes in H.264 and H.265 streams.
+
+/// Video codecs whose random access points can be found from NAL units.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Codec {
+    /// H.264 / AVC
+    H264,
+    /// H.265 / HEVC
+    H265,
+}
+
+impl Codec {
+    /// The codec of a stream of the PMT, `None` for other codecs.
+    pub fn from_stream_type(stream_type: u8) -> Option<Self> {
+        match stream_type {
+            0x1b => Some(Codec::H264),
+            0x24 => Some(Codec::H265),
+            _ => None,
+        }
+    }
+
+    /// Classifies a NAL unit by its first header byte: `Some(true)` for an
+    /// IDR (H.264) or IRAP (H.265) picture, `Some(false)` for any other
+    /// picture, and `None` for non-VCL units.
+    fn is_random_access(self, nal_header: u8) -> Option<bool> {
+        match self {
+            Codec::H264 => {
+                let nal_type = nal_header & 0x1f;
+                (1..=5).contains(&nal_type).then_some(nal_type == 5)
+            }
+            Codec::H265 => {
+                let nal_type = (nal_header >> 1) & 0x3f;
+                (nal_type <= 31).then_some((16..=21).contains(&nal_type))
+            }
+        }
+    }
+}
+
+/// Finds the first picture of an access unit in Annex B byte stream data,
+/// which may be split at any point across calls.
+pub struct NalScanner {
+    codec: Codec,
+    zeros: usize,
+    header_next: bool,
+}
+
+impl NalScanner {
+    /// A scanner for a stream of `codec`, starting before any NAL unit.
+    pub fn new(codec: Codec) -> Self {
+        Self {
+            codec,
+            zeros: 0,
+            header_next: false,
+        }
+    }
+
+    /// Feeds the next bytes of the stream. Returns whether the first picture
+    /// is a random access point once it is found.
+    pub fn push(&mut self, data: &[u8]) -> Option<bool> {
+        for byte in data {
+            if self.header_next {
+                self.header_next = false;
+                if let Some(random_access) = self.codec.is_random_access(*byte) {
+                    return Some(random_access);
+                }
+            }
+
+            if *byte == 0x01 && self.zeros >= 2 {
+                // start code, the NAL unit header follows
+                self.header_next = true;
+            }
+            if *byte == 0x00 {
+                self.zeros += 1;
+            } else {
+                self.zeros = 0;
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_the_first_h264_picture() {
+        // SPS and PPS are skipped, the IDR slice decides
+        let data = [
+            0, 0, 0, 1, 0x67, 0x42, 0, 0, 1, 0x68, 0xce, 0, 0, 1, 0x65, 0x88,
+        ];
+        assert_eq!(NalScanner::new(Codec::H264).push(&data), Some(true));
+
+        let data = [0, 0, 1, 0x09, 0xf0, 0, 0, 0, 1, 0x41, 0x9a];
+        assert_eq!(NalScanner::new(Codec::H264).push(&data), Some(false));
+
+        // no start code, only a 0x65 byte
+        let data = [0x12, 0, 2, 0x65, 0, 1, 0x65];
+        assert_eq!(NalScanner::new(Codec::H264).push(&data), None);
+    }
+
+    #[test]
+    fn finds_start_codes_split_across_pushes() {
+        let data = [0x06, 0x05, 0x10, 0, 0, 0, 1, 0x65, 0x88];
+        for split in 0..data.len() {
+            let mut scanner = NalScanner::new(Codec::H264);
+            let found = scanner
+                .push(&data[..split])
+                .or_else(|| scanner.push(&data[split..]));
+            assert_eq!(found, Some(true), "split at {split}");
+        }
+    }
+
+    #[test]
+    fn finds_h265_irap_pictures() {
+        // VPS, then each IRAP type, BLA to CRA
+        for nal_type in 16..=21u8 {
+            let data = [0, 0, 1, 0x40, 0x01, 0, 0, 1, nal_type << 1, 0x01];
+            assert_eq!(
+                NalScanner::new(Codec::H265).push(&data),
+                Some(true),
+                "type {nal_type}"
+            );
+        }
+
+        // TRAIL_R
+        let data = [0, 0, 0, 1, 0x02, 0x01];
+        assert_eq!(NalScanner::new(Codec::H265).push(&data), Some(false));
+        // the IDR header byte of H.264 is no picture in H.265
+        let data = [0, 0, 1, 0x65, 0x01];
+        assert_eq!(NalScanner::new(Codec::H265).push(&data), None);
+    }
+
+    #[test]
+    fn knows_the_codecs_of_stream_types() {
+        assert_eq!(Codec::from_stream_type(0x1b), Some(Codec::H264));
+        assert_eq!(Codec::from_stream_type(0x24), Some(Codec::H265));
+        assert_eq!(Codec::from_stream_type(0x02), None);
+    }
+}
@@ -0,0 +1,519 @@
+//! Reading transport stream packets from videos.
+
+use crate::error::MtfError;
+use anyhow::bail;
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::File,
+    io::{BufRead, BufReader, ErrorKind, Read},
+};
+
+/// Size of a plain transport stream packet.
+pub const TS_PACKET_SIZE: usize = 188;
+
+/// Supported packet sizes: plain TS, M2TS/BDAV (4-byte timestamp before the
+/// packet) and DVB with 16 bytes of Reed-Solomon parity after it.
+pub const PACKET_SIZES: [usize; 3] = [188, 192, 204];
+
+/// Number of consecutive sync bytes required to detect a packet size.
+const DETECT_PACKETS: usize = 5;
+
+/// Bytes read from the start of a video to detect the packet size, enough
+/// for [`DETECT_PACKETS`] of the largest size after a partial one.
+const DETECT_BYTES: usize = (DETECT_PACKETS + 1) * 204;
+
+/// Offset of the sync byte in a packet of the given size.
+pub fn sync_offset(packet_size: usize) -> usize {
+    if packet_size == 192 {
+        4
+    } else {
+        0
+    }
+}
+
+/// Detects the packet size from the periodicity of sync bytes at the start
+/// of `data`, returning `None` if no size fits.
+pub fn detect_packet_size(data: &[u8]) -> Option<usize> {
+    let mut best: Option<(usize, usize)> = None;
+    for size in PACKET_SIZES {
+        let count = (data.len() / size).clamp(1, DETECT_PACKETS);
+        let sync = sync_offset(size);
+        let start = (0..size)
+            .find(|start| (0..count).all(|i| data.get(start + sync + i * size) == Some(&0x47)));
+        if let Some(start) = start {
+            // prefer the size which locks earliest, sizes are tried smallest first
+            if best.is_none_or(|(_, best_start)| start < best_start) {
+                best = Some((size, start));
+            }
+        }
+    }
+    best.map(|(size, _)| size)
+}
+
+/// Checks a requested packet size, or detects it from the start of the
+/// video `data` if `None`, defaulting to 188.
+pub fn resolve_packet_size(data: &[u8], packet_size: Option<usize>) -> anyhow::Result<usize> {
+    Ok(match packet_size {
+        Some(size) if !PACKET_SIZES.contains(&size) => {
+            bail!("unsupported packet size {size}, expected one of {PACKET_SIZES:?}")
+        }
+        Some(size) => size,
+        None => detect_packet_size(data).unwrap_or(TS_PACKET_SIZE),
+    })
+}
+
+/// Packets with a sync byte at packet spacing required to lock on the
+/// packet boundaries.
+pub const SYNC_LOCK_PACKETS: usize = 5;
+
+/// Consecutive packets without sync byte after which the lock is dropped and
+/// the packet boundaries are searched again.
+pub const SYNC_LOSS_PACKETS: usize = 3;
+
+/// A transport stream packet read from a video.
+pub struct Packet {
+    /// Byte offset of the packet in the video, including any prefix before
+    /// the sync byte
+    pub offset: u64,
+    /// The 188-byte transport stream packet, without M2TS timestamp or
+    /// FEC parity
+    pub data: [u8; TS_PACKET_SIZE],
+}
+
+/// A [`Packet`] borrowed from where it was read, valid until the next one
+/// is read.
+#[derive(Debug, Clone, Copy)]
+pub struct PacketRef<'a> {
+    /// Byte offset of the packet in the video, like [`Packet::offset`]
+    pub offset: u64,
+    /// The 188-byte transport stream packet
+    pub data: &'a [u8; TS_PACKET_SIZE],
+}
+
+impl PacketRef<'_> {
+    /// Copies the packet out of where it was read.
+    pub fn to_packet(self) -> Packet {
+        Packet {
+            offset: self.offset,
+            data: *self.data,
+        }
+    }
+}
+
+/// Something packets of a video are read from, such as a [`PacketReader`]
+/// or a [`crate::mmap::MmapScanner`].
+///
+/// Packets are lent rather than returned, so that a source holding the
+/// video in memory does not copy them.
+pub trait PacketSource {
+    /// Reads the next packet, or returns `None` at the end of the video.
+    fn next_packet_ref(&mut self) -> anyhow::Result<Option<PacketRef<'_>>>;
+}
+
+/// Bytes `start..end` of a video.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ByteRange {
+    /// Offset of the first byte
+    pub start: u64,
+    /// Offset after the last byte
+    pub end: u64,
+}
+
+/// Reads packets of a video, skipping garbage between them.
+///
+/// The reader locks on packet boundaries once [`SYNC_LOCK_PACKETS`] sync
+/// bytes are found at packet spacing, so a 0x47 in a payload is not mistaken
+/// for a packet start. Packets without sync byte are skipped while locked,
+/// and after [`SYNC_LOSS_PACKETS`] of them in a row the boundaries are
+/// searched again. Everything which was not returned as a packet is recorded
+/// in [`PacketReader::skipped`].
+///
+/// Bytes read ahead while searching for boundaries are kept and read again,
+/// so the video is read once from start to end and can be a pipe.
+pub struct PacketReader<R> {
+    reader: BufReader<R>,
+    packet_size: usize,
+    buf: Vec<u8>,
+    /// Bytes read ahead and given back, read before `reader`
+    pending: Vec<u8>,
+    /// Offset of the next byte read, from `pending` or `reader`
+    position: u64,
+    locked: bool,
+    /// Whether a packet was returned yet
+    found: bool,
+    misses: usize,
+    /// The packets of the current run of packets without sync byte
+    missed: Vec<u8>,
+    skip_start: Option<u64>,
+    skipped: Vec<ByteRange>,
+}
+
+impl PacketReader<File> {
+    /// Opens a reader for the video at `path`, like [`PacketReader::new`].
+    pub fn open<P>(path: P, packet_size: Option<usize>) -> anyhow::Result<Self>
+    where
+        P: AsRef<std::path::Path>,
+    {
+        Self::new(File::open(path)?, packet_size)
+    }
+}
+
+impl<R> PacketReader<R>
+where
+    R: Read,
+{
+    /// Creates a reader for packets of `packet_size` bytes, or detects the
+    /// size from the start of the video if `None`, defaulting to 188.
+    pub fn new(reader: R, packet_size: Option<usize>) -> anyhow::Result<Self> {
+        let mut reader = BufReader::with_capacity(TS_PACKET_SIZE * 64, reader);
+        let head = read_head(&mut reader)?;
+        let packet_size = resolve_packet_size(&head, packet_size)?;
+
+        Ok(Self {
+            reader,
+            packet_size,
+            buf: vec![0; packet_size * (SYNC_LOCK_PACKETS + 1)],
+            pending: head,
+            position: 0,
+            locked: false,
+            found: false,
+            misses: 0,
+            missed: Vec::new(),
+            skip_start: None,
+            skipped: Vec::new(),
+        })
+    }
+
+    /// Offsets packets by `position`, for a reader which starts at that
+    /// offset of the video.
+    pub fn starting_at(mut self, position: u64) -> Self {
+        self.position = position;
+        self
+    }
+
+    /// Size of the packets, with any M2TS timestamp or FEC parity.
+    pub fn packet_size(&self) -> usize {
+        self.packet_size
+    }
+
+    /// Byte ranges skipped so far because they were not part of a packet.
+    pub fn skipped(&self) -> &[ByteRange] {
+        &self.skipped
+    }
+
+    /// The raw bytes of the packet last returned by
+    /// [`PacketReader::next_packet`], including any M2TS timestamp or FEC
+    /// parity.
+    pub fn raw_packet(&self) -> &[u8] {
+        &self.buf[..self.packet_size]
+    }
+
+    /// Reads the next packet, or returns `None` at the end of the video.
+    pub fn next_packet(&mut self) -> anyhow::Result<Option<Packet>> {
+        Ok(self.next_packet_ref()?.map(PacketRef::to_packet))
+    }
+
+    /// Reads the next packet like [`PacketReader::next_packet`], lending it
+    /// from the buffer of the reader.
+    pub fn next_packet_ref(&mut self) -> anyhow::Result<Option<PacketRef<'_>>> {
+        let size = self.packet_size;
+        let sync = sync_offset(size);
+        loop {
+            if !self.locked && !self.acquire_sync()? {
+                self.end_skip(self.position);
+                if !self.found && !self.skipped.is_empty() {
+                    // not a transport stream
+                    bail!(MtfError::BadSync);
+                }
+                return Ok(None);
+            }
+
+            let offset = self.position;
+            let read = self.fill(size)?;
+            if read < size {
+                // truncated packet at the end of file
+                if read > 0 {
+                    self.start_skip(offset);
+                }
+                self.end_skip(self.position);
+                return Ok(None);
+            }
+
+            if self.buf[sync] != 0x47 {
+                self.start_skip(offset);
+                self.misses += 1;
+                self.missed.extend_from_slice(&self.buf[..size]);
+                if self.misses >= SYNC_LOSS_PACKETS {
+                    // the boundaries moved somewhere in the missed packets
+                    let missed = std::mem::take(&mut self.missed);
+                    self.unread(&missed);
+                    self.locked = false;
+                }
+                continue;
+            }
+
+            self.misses = 0;
+            self.missed.clear();
+            self.end_skip(offset);
+            self.found = true;
+
+            let data = self.buf[sync..sync + TS_PACKET_SIZE].try_into().unwrap();
+            return Ok(Some(PacketRef { offset, data }));
+        }
+    }
+
+    /// Searches for the next packet boundary, positioning the reader on it.
+    /// Returns `false` if the end of file is reached before.
+    fn acquire_sync(&mut self) -> anyhow::Result<bool> {
+        let size = self.packet_size;
+        let sync = sync_offset(size);
+        let window = self.buf.len();
+        loop {
+            let start = self.position;
+            let read = self.fill(window)?;
+            let eof = read < window;
+
+            let candidate = (0..size.min(read)).find(|&position| {
+                // at the end of file, lock on whatever packets are left
+                let count = ((read - position) / size).min(SYNC_LOCK_PACKETS);
+                (count == SYNC_LOCK_PACKETS || (eof && count > 0))
+                    && (0..count).all(|i| self.buf[position + sync + i * size] == 0x47)
+            });
+
+            if let Some(position) = candidate {
+                if position > 0 {
+                    self.start_skip(start);
+                }
+                self.unread_buf(position, read);
+                self.locked = true;
+                self.misses = 0;
+                self.missed.clear();
+                return Ok(true);
+            }
+
+            if read > 0 {
+                self.start_skip(start);
+            }
+            if eof {
+                return Ok(false);
+            }
+            self.unread_buf(size, read);
+        }
+    }
+
+    fn start_skip(&mut self, offset: u64) {
+        self.skip_start.get_or_insert(offset);
+    }
+
+    fn end_skip(&mut self, offset: u64) {
+        if let Some(start) = self.skip_start.take() {
+            if offset > start {
+                self.skipped.push(ByteRange { start, end: offset });
+            }
+        }
+    }
+
+    /// Gives back bytes `start..end` of the buffer, which were the last ones
+    /// read.
+    fn unread_buf(&mut self, start: usize, end: usize) {
+        let buf = std::mem::take(&mut self.buf);
+        self.unread(&buf[start..end]);
+        self.buf = buf;
+    }
+
+    /// Gives back the last bytes read, to be read again.
+    fn unread(&mut self, data: &[u8]) {
+        self.pending.splice(0..0, data.iter().copied());
+        self.position -= data.len() as u64;
+    }
+
+    /// Reads up to `len` bytes into the buffer, returning the bytes read.
+    fn fill(&mut self, len: usize) -> std::io::Result<usize> {
+        let mut read = len.min(self.pending.len());
+        self.buf[..read].copy_from_slice(&self.pending[..read]);
+        self.pending.drain(..read);
+        while read < len {
+            match self.reader.read(&mut self.buf[read..len]) {
+                Ok(0) => break,
+                Ok(n) => read += n,
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        self.position += read as u64;
+        Ok(read)
+    }
+}
+
+/// Reads the start of a video until [`DETECT_BYTES`] or the end of file, as
+/// a pipe may return fewer bytes at a time than needed to detect the packet
+/// size.
+fn read_head<R: Read>(reader: &mut BufReader<R>) -> std::io::Result<Vec<u8>> {
+    let mut head = Vec::new();
+    while head.len() < DETECT_BYTES {
+        let data = match reader.fill_buf() {
+            Ok(data) => data,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        if data.is_empty() {
+            break;
+        }
+        let len = data.len();
+        head.extend_from_slice(data);
+        reader.consume(len);
+    }
+    Ok(head)
+}
+
+impl<R> PacketSource for PacketReader<R>
+where
+    R: Read,
+{
+    fn next_packet_ref(&mut self) -> anyhow::Result<Option<PacketRef<'_>>> {
+        PacketReader::next_packet_ref(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{packet, VIDEO_PID};
+    use std::io::Cursor;
+
+    /// `count` packets with distinct payloads and no 0x47 after the sync
+    /// byte.
+    fn packets(count: usize) -> Vec<u8> {
+        (0..count)
+            .flat_map(|i| packet(VIDEO_PID, false, i as u8 + 1))
+            .collect()
+    }
+
+    /// Offsets of all packets and the skipped ranges of `data`.
+    fn read(data: Vec<u8>) -> (Vec<u64>, Vec<ByteRange>) {
+        let mut reader = PacketReader::new(Cursor::new(data), Some(TS_PACKET_SIZE)).unwrap();
+        let mut offsets = Vec::new();
+        while let Some(packet) = reader.next_packet().unwrap() {
+            assert_eq!(packet.data[0], 0x47);
+            offsets.push(packet.offset);
+        }
+        (offsets, reader.skipped().to_vec())
+    }
+
+    fn range(start: u64, end: u64) -> ByteRange {
+        ByteRange { start, end }
+    }
+
+    #[test]
+    fn reads_clean_packets() {
+        let (offsets, skipped) = read(packets(8));
+        assert_eq!(offsets, (0..8).map(|i| i * 188).collect::<Vec<_>>());
+        assert!(skipped.is_empty());
+    }
+
+    #[test]
+    fn skips_leading_garbage() {
+        let mut data = vec![0x47, 0, 0x47, 0, 0, 0, 0];
+        data.extend(packets(8));
+        let (offsets, skipped) = read(data);
+        assert_eq!(offsets, (0..8).map(|i| 7 + i * 188).collect::<Vec<_>>());
+        assert_eq!(skipped, vec![range(0, 7)]);
+    }
+
+    #[test]
+    fn skips_inserted_garbage_with_sync_bytes() {
+        let mut garbage = vec![0u8; 50];
+        garbage[10] = 0x47;
+        garbage[20] = 0x47;
+        let mut data = packets(12);
+        data.splice(5 * 188..5 * 188, garbage);
+
+        let (offsets, skipped) = read(data);
+        let expected: Vec<u64> = (0..12)
+            .map(|i| if i < 5 { i * 188 } else { 50 + i * 188 })
+            .collect();
+        assert_eq!(offsets, expected);
+        assert_eq!(skipped, vec![range(940, 990)]);
+    }
+
+    #[test]
+    fn resyncs_after_a_dropped_byte() {
+        let mut data = packets(12);
+        data.remove(4 * 188 + 100);
+
+        let (offsets, skipped) = read(data);
+        // the short packet swallows the sync byte of the next one, which is
+        // lost with it
+        let expected: Vec<u64> = (0..5)
+            .map(|i| i * 188)
+            .chain((6..12).map(|i| i * 188 - 1))
+            .collect();
+        assert_eq!(offsets, expected);
+        assert_eq!(skipped, vec![range(940, 1127)]);
+    }
+
+    #[test]
+    fn skips_a_truncated_tail_packet() {
+        let mut data = packets(10);
+        data.extend_from_slice(&packet(VIDEO_PID, false, 0x11)[..100]);
+
+        let (offsets, skipped) = read(data);
+        assert_eq!(offsets.len(), 10);
+        assert_eq!(skipped, vec![range(1880, 1980)]);
+    }
+
+    #[test]
+    fn rejects_data_without_packets() {
+        let data: Vec<u8> = (0..5000).map(|i| (i * 7 % 251) as u8).collect();
+        let mut reader = PacketReader::new(Cursor::new(data), None).unwrap();
+        let error = reader.next_packet().err().unwrap();
+        assert!(matches!(error.downcast_ref(), Some(MtfError::BadSync)));
+
+        // an empty video has no packet but is not garbage either
+        let mut reader = PacketReader::new(Cursor::new(Vec::new()), None).unwrap();
+        assert!(reader.next_packet().unwrap().is_none());
+    }
+
+    #[test]
+    fn detects_packet_sizes() {
+        let plain = packets(6);
+        let m2ts: Vec<u8> = plain
+            .chunks(188)
+            .flat_map(|packet| [&[0, 0, 0, 0][..], packet].concat())
+            .collect();
+        let fec: Vec<u8> = plain
+            .chunks(188)
+            .flat_map(|packet| [packet, &[0; 16][..]].concat())
+            .collect();
+        assert_eq!(detect_packet_size(&plain), Some(188));
+        assert_eq!(detect_packet_size(&m2ts), Some(192));
+        assert_eq!(detect_packet_size(&fec), Some(204));
+    }
+
+    /// A reader returning a few bytes at a time, like a pipe.
+    struct ShortReads(Cursor<Vec<u8>>);
+
+    impl Read for ShortReads {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let len = buf.len().min(100);
+            self.0.read(&mut buf[..len])
+        }
+    }
+
+    #[test]
+    fn detects_the_packet_size_of_a_pipe() {
+        let fec: Vec<u8> = packets(8)
+            .chunks(188)
+            .flat_map(|packet| [packet, &[0; 16][..]].concat())
+            .collect();
+        let mut reader = PacketReader::new(ShortReads(Cursor::new(fec)), None).unwrap();
+        assert_eq!(reader.packet_size(), 204);
+
+        let mut offsets = Vec::new();
+        while let Some(packet) = reader.next_packet().unwrap() {
+            offsets.push(packet.offset);
+        }
+        assert_eq!(offsets, (0..8).map(|i| i * 204).collect::<Vec<_>>());
+        assert!(reader.skipped().is_empty());
+    }
+}
@@ -0,0 +1,114 @@
+//! Headers of PES packets.
+
+/// Returns the elementary stream data of the start of a PES packet, after
+/// its header, or `None` if `payload` does not start a PES packet.
+pub fn pes_payload(payload: &[u8]) -> Option<&[u8]> {
+    if payload.len() < 6 || payload[..3] != [0x00, 0x00, 0x01] {
+        return None;
+    }
+
+    let stream_id = payload[3];
+    if has_optional_header(stream_id) {
+        let header_data_length = *payload.get(8)? as usize;
+        payload.get(9 + header_data_length..)
+    } else {
+        payload.get(6..)
+    }
+}
+
+/// Whether PES packets of a stream carry the optional header with flags and
+/// timestamps, which is the case for everything but a few system streams.
+fn has_optional_header(stream_id: u8) -> bool {
+    !matches!(
+        stream_id,
+        0xbc | 0xbe | 0xbf | 0xf0 | 0xf1 | 0xf2 | 0xf8 | 0xff
+    )
+}
+
+/// Returns the PTS of the start of a PES packet, in 90 kHz units.
+pub fn pts(payload: &[u8]) -> Option<u64> {
+    pes_timestamps(payload).map(|(pts, _)| pts)
+}
+
+/// Returns the PTS and DTS of the start of a PES packet, in 90 kHz units.
+/// The DTS is only present when it differs from the PTS.
+pub fn pes_timestamps(payload: &[u8]) -> Option<(u64, Option<u64>)> {
+    if payload.len() < 9 || payload[..3] != [0x00, 0x00, 0x01] || !has_optional_header(payload[3]) {
+        return None;
+    }
+
+    let pts_dts_flags = payload[7] >> 6;
+    match pts_dts_flags {
+        0b10 => Some((timestamp(payload.get(9..14)?), None)),
+        0b11 => Some((
+            timestamp(payload.get(9..14)?),
+            Some(timestamp(payload.get(14..19)?)),
+        )),
+        _ => None,
+    }
+}
+
+/// Decodes a 33-bit timestamp spread over 5 bytes with marker bits.
+fn timestamp(bytes: &[u8]) -> u64 {
+    ((bytes[0] as u64 >> 1) & 0x07) << 30
+        | (bytes[1] as u64) << 22
+        | (bytes[2] as u64 >> 1) << 15
+        | (bytes[3] as u64) << 7
+        | bytes[4] as u64 >> 1
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Encodes a 33-bit timestamp with its 4-bit prefix and marker bits.
+    fn encode(prefix: u8, timestamp: u64) -> [u8; 5] {
+        [
+            prefix << 4 | ((timestamp >> 30) as u8 & 0x07) << 1 | 1,
+            (timestamp >> 22) as u8,
+            ((timestamp >> 15) as u8) << 1 | 1,
+            (timestamp >> 7) as u8,
+            (timestamp as u8) << 1 | 1,
+        ]
+    }
+
+    /// The start of a video PES packet with the given timestamps.
+    fn pes(pts: Option<u64>, dts: Option<u64>) -> Vec<u8> {
+        let flags = (pts.is_some() as u8) << 7 | (dts.is_some() as u8) << 6;
+        let mut header = Vec::new();
+        if let Some(pts) = pts {
+            header.extend(encode(if dts.is_some() { 0b0011 } else { 0b0010 }, pts));
+        }
+        if let Some(dts) = dts {
+            header.extend(encode(0b0001, dts));
+        }
+
+        let mut data = vec![0x00, 0x00, 0x01, 0xe0, 0x00, 0x00, 0x80, flags];
+        data.push(header.len() as u8);
+        data.extend(header);
+        data.extend([0x00, 0x00, 0x00, 0x01, 0x65]);
+        data
+    }
+
+    #[test]
+    fn reads_the_pts_and_dts() {
+        let time = (1 << 32) + 123_456;
+        assert_eq!(pes_timestamps(&pes(None, None)), None);
+        assert_eq!(pes_timestamps(&pes(Some(time), None)), Some((time, None)));
+        assert_eq!(
+            pes_timestamps(&pes(Some(time), Some(time - 3003))),
+            Some((time, Some(time - 3003)))
+        );
+        assert_eq!(pts(&pes(Some(time), Some(time - 3003))), Some(time));
+    }
+
+    #[test]
+    fn skips_the_pes_header() {
+        let data = pes(Some(900), Some(0));
+        assert_eq!(
+            pes_payload(&data),
+            Some(&[0x00, 0x00, 0x00, 0x01, 0x65][..])
+        );
+        assert_eq!(pes_payload(&[0x00, 0x00, 0x02, 0xe0, 0x00, 0x00]), None);
+    }
+}
@@ -0,0 +1,249 @@
+//! Matching the segments of HLS playlists.
+
+use crate::matching::{find_segment, MatchResult};
+use crate::segment::HashFile;
+use anyhow::bail;
+use std::path::{Path, PathBuf};
+
+/// A media segment of a playlist.
+#[derive(Debug, Clone)]
+pub struct PlaylistSegment {
+    /// Path of the segment, resolved against the playlist directory
+    pub path: PathBuf,
+    /// Duration from `#EXTINF`, in seconds
+    pub duration: Option<f64>,
+}
+
+/// Parses a local HLS media playlist. Remote segments, byte ranges and
+/// master playlists are not supported.
+pub fn parse_media_playlist<P>(path: P) -> anyhow::Result<Vec<PlaylistSegment>>
+where
+    P: AsRef<Path>,
+{
+    let path = path.as_ref();
+    let content = std::fs::read_to_string(path)?;
+    let base = path.parent().unwrap_or(Path::new(""));
+
+    let mut lines = content.lines().map(str::trim);
+    if lines.next().map(|line| line.trim_start_matches('\u{feff}')) != Some("#EXTM3U") {
+        bail!("{} is not a M3U8 playlist", path.display());
+    }
+
+    let mut segments = Vec::new();
+    let mut duration = None;
+    for line in lines {
+        if let Some(value) = line.strip_prefix("#EXTINF:") {
+            let value = value.split(',').next().unwrap_or_default();
+            duration = value.trim().parse().ok();
+        } else if line.starts_with("#EXT-X-STREAM-INF") {
+            bail!(
+                "{} is a master playlist, use one of its media playlists",
+                path.display()
+            );
+        } else if line.starts_with("#EXT-X-BYTERANGE") {
+            bail!("byte range segments are not supported");
+        } else if line.is_empty() || line.starts_with('#') {
+            continue;
+        } else if line.contains("://") {
+            bail!("remote segment {line} is not supported, download it first");
+        } else {
+            segments.push(PlaylistSegment {
+                path: base.join(line),
+                duration: duration.take(),
+            });
+        }
+    }
+    Ok(segments)
+}
+
+/// How a playlist segment matched the hash file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PlaylistStatus {
+    /// Found once, after the previous playlist segment
+    Ok,
+    /// Not found in the hash file, or could not be hashed
+    Missing,
+    /// Matches several runs of the hash file, or overlaps the run of an
+    /// earlier playlist segment
+    Duplicated,
+    /// Matches a segment before the one of the previous playlist segment
+    OutOfOrder,
+}
+
+/// Result of matching one playlist segment.
+#[derive(Debug)]
+pub struct PlaylistMatch {
+    /// The segment of the playlist
+    pub segment: PlaylistSegment,
+    /// The matching segments of the hash file, or why there are none
+    pub result: anyhow::Result<MatchResult>,
+    /// First segment of the hash file run chosen for this playlist segment:
+    /// the first one after the previous playlist segment if possible
+    pub index: Option<usize>,
+    /// How the segment matched
+    pub status: PlaylistStatus,
+}
+
+/// Matches every segment of a playlist against a hash file.
+pub fn match_playlist(hashes: &HashFile, segments: Vec<PlaylistSegment>) -> Vec<PlaylistMatch> {
+    let mut matches = Vec::with_capacity(segments.len());
+    let mut seen = vec![false; hashes.len()];
+    let mut previous: Option<usize> = None;
+
+    for segment in segments {
+        let result = find_segment(hashes, &segment.path);
+        let (indices, count) = result
+            .as_ref()
+            .map(|result| (result.indices.as_slice(), result.segments))
+            .unwrap_or_default();
+
+        let index = indices
+            .iter()
+            .find(|index| previous.is_none_or(|previous| **index > previous))
+            .or(indices.first())
+            .copied();
+        let status = match index {
+            None => PlaylistStatus::Missing,
+            Some(index) if indices.len() > 1 || seen[index..index + count].contains(&true) => {
+                PlaylistStatus::Duplicated
+            }
+            Some(index) if previous.is_some_and(|previous| index < previous) => {
+                PlaylistStatus::OutOfOrder
+            }
+            Some(_) => PlaylistStatus::Ok,
+        };
+
+        if let Some(index) = index {
+            seen[index..index + count].fill(true);
+            previous = Some(index + count - 1);
+        }
+        matches.push(PlaylistMatch {
+            segment,
+            result,
+            index,
+            status,
+        });
+    }
+    matches
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{video, TempDir, TempFile};
+    use std::fs;
+
+    /// Writes a playlist in a new directory and parses it.
+    fn parse(content: &str) -> (TempDir, anyhow::Result<Vec<PlaylistSegment>>) {
+        let dir = TempDir::new();
+        let path = dir.path().join("index.m3u8");
+        fs::write(&path, content).unwrap();
+        let segments = parse_media_playlist(&path);
+        (dir, segments)
+    }
+
+    #[test]
+    fn parses_a_media_playlist() {
+        // with a byte order mark and Windows line endings
+        let content = [
+            "\u{feff}#EXTM3U",
+            "#EXT-X-TARGETDURATION:4",
+            "",
+            "#EXTINF:4.000,first",
+            "seg0.ts",
+            "# comment",
+            "sub/seg1.ts",
+            "#EXTINF:3.5,",
+            "seg2.ts",
+        ]
+        .join("\r\n");
+        let (dir, segments) = parse(&content);
+        let segments: Vec<_> = segments
+            .unwrap()
+            .into_iter()
+            .map(|segment| (segment.path, segment.duration))
+            .collect();
+        assert_eq!(
+            segments,
+            vec![
+                (dir.path().join("seg0.ts"), Some(4.0)),
+                (dir.path().join("sub/seg1.ts"), None),
+                (dir.path().join("seg2.ts"), Some(3.5)),
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_unsupported_playlists() {
+        for (content, error) in [
+            ("seg0.ts\n", "not a M3U8 playlist"),
+            (
+                "#EXTM3U\n#EXT-X-STREAM-INF:BANDWIDTH=1280000\nlow.m3u8\n",
+                "master playlist",
+            ),
+            (
+                "#EXTM3U\n#EXTINF:4,\n#EXT-X-BYTERANGE:1000@0\nall.ts\n",
+                "byte range",
+            ),
+            (
+                "#EXTM3U\n#EXTINF:4,\nhttp://example.com/seg0.ts\n",
+                "remote",
+            ),
+        ] {
+            let (_dir, segments) = parse(content);
+            let message = segments.unwrap_err().to_string();
+            assert!(message.contains(error), "{message}");
+        }
+    }
+
+    #[test]
+    fn classifies_missing_duplicated_and_out_of_order_segments() {
+        let file = TempFile::new(&video(&[1, 2, 3, 4, 5]));
+        let hashes = HashFile::new(file.path(), Default::default()).unwrap();
+
+        let files: Vec<TempFile> = [&[1][..], &[2], &[4, 5], &[3], &[9], &[2]]
+            .into_iter()
+            .map(|fills| TempFile::new(&video(fills)))
+            .collect();
+        let segments = files
+            .iter()
+            .map(|file| PlaylistSegment {
+                path: file.path().to_path_buf(),
+                duration: None,
+            })
+            .collect();
+
+        let found: Vec<_> = match_playlist(&hashes, segments)
+            .into_iter()
+            .map(|found| (found.index, found.status))
+            .collect();
+        assert_eq!(
+            found,
+            vec![
+                (Some(0), PlaylistStatus::Ok),
+                (Some(1), PlaylistStatus::Ok),
+                (Some(3), PlaylistStatus::Ok),
+                (Some(2), PlaylistStatus::OutOfOrder),
+                (None, PlaylistStatus::Missing),
+                (Some(1), PlaylistStatus::Duplicated),
+            ]
+        );
+    }
+
+    #[test]
+    fn reports_segments_found_several_times_as_duplicated() {
+        let file = TempFile::new(&video(&[1, 2, 3, 2]));
+        let hashes = HashFile::new(file.path(), Default::default()).unwrap();
+        let segment = TempFile::new(&video(&[2]));
+
+        let found = match_playlist(
+            &hashes,
+            vec![PlaylistSegment {
+                path: segment.path().to_path_buf(),
+                duration: None,
+            }],
+        );
+        assert_eq!(found[0].index, Some(1));
+        assert_eq!(found[0].status, PlaylistStatus::Duplicated);
+    }
+}
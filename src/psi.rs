@@ -0,0 +1,583 @@
+//! Program specific information: the PAT and PMT.
+
+use crate::{
+    error::MtfError,
+    header::{payload, MpegtsHeader},
+};
+use anyhow::bail;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+};
+
+/// PID of the program association table.
+pub const PAT_PID: u16 = 0;
+
+/// Parses a PID, in decimal or as 0x-prefixed hexadecimal.
+pub fn parse_pid(s: &str) -> anyhow::Result<u16> {
+    let pid = match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u16::from_str_radix(hex, 16)?,
+        None => s.parse()?,
+    };
+    if pid > 0x1fff {
+        bail!("PIDs are 13-bit, expected at most 0x1fff");
+    }
+    Ok(pid)
+}
+
+/// CRC-32 of MPEG-2 sections. The CRC of a whole valid section, including
+/// its trailing CRC field, is 0.
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xffffffffu32;
+    for byte in data {
+        crc ^= (*byte as u32) << 24;
+        for _ in 0..8 {
+            crc = if crc & 0x80000000 != 0 {
+                (crc << 1) ^ 0x04c11db7
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+/// A PSI section with the long syntax, after CRC validation.
+pub struct Section<'a> {
+    /// Kind of table, 0x00 for a PAT and 0x02 for a PMT
+    pub table_id: u8,
+    /// Transport stream ID of a PAT, program number of a PMT
+    pub table_id_extension: u16,
+    /// Version of the table, incremented when it changes
+    pub version: u8,
+    /// Whether the table applies now rather than next
+    pub current_next: bool,
+    /// Index of the section in its table
+    pub section_number: u8,
+    /// Index of the last section of the table
+    pub last_section_number: u8,
+    /// Section data after the header, without CRC
+    pub body: &'a [u8],
+}
+
+impl<'a> Section<'a> {
+    /// Parses a whole section, checking its length and CRC.
+    pub fn parse(data: &'a [u8]) -> anyhow::Result<Self> {
+        if data.len() < 12 {
+            bail!(MtfError::InvalidSection("section too short"));
+        }
+        if data[1] & 0x80 == 0 {
+            bail!(MtfError::InvalidSection("not a long section"));
+        }
+        let length = 3 + (((data[1] & 0x0f) as usize) << 8 | data[2] as usize);
+        if length != data.len() {
+            bail!(MtfError::InvalidSection("invalid section length"));
+        }
+        if crc32(data) != 0 {
+            bail!(MtfError::InvalidSection("CRC mismatch"));
+        }
+
+        Ok(Self {
+            table_id: data[0],
+            table_id_extension: u16::from_be_bytes([data[3], data[4]]),
+            version: (data[5] >> 1) & 0x1f,
+            current_next: data[5] & 0x01 != 0,
+            section_number: data[6],
+            last_section_number: data[7],
+            body: &data[8..length - 4],
+        })
+    }
+}
+
+/// Program association table: programs and the PIDs of their PMT.
+pub struct Pat {
+    /// Identifier of the transport stream in its network
+    pub transport_stream_id: u16,
+    /// Version of the PAT
+    pub version: u8,
+    /// `(program_number, pmt_pid)`, without the network PID
+    pub programs: Vec<(u16, u16)>,
+}
+
+impl Pat {
+    /// Parses the programs of a PAT section.
+    pub fn parse(section: &Section) -> anyhow::Result<Self> {
+        if section.table_id != 0x00 {
+            bail!(MtfError::InvalidSection("not a PAT"));
+        }
+
+        let programs = section
+            .body
+            .chunks_exact(4)
+            .map(|entry| {
+                let number = u16::from_be_bytes([entry[0], entry[1]]);
+                let pid = u16::from_be_bytes([entry[2], entry[3]]) & 0x1fff;
+                (number, pid)
+            })
+            .filter(|(number, _)| *number != 0)
+            .collect();
+
+        Ok(Self {
+            transport_stream_id: section.table_id_extension,
+            version: section.version,
+            programs,
+        })
+    }
+}
+
+/// An elementary stream of a program.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ElementaryStream {
+    /// Codec of the stream, as defined by ISO/IEC 13818-1
+    pub stream_type: u8,
+    /// PID of the packets of the stream
+    pub pid: u16,
+}
+
+/// Kind of an elementary stream, from its stream type.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StreamKind {
+    /// MPEG-1/2 video, MPEG-4 part 2, H.264, H.265, VC-1...
+    Video,
+    /// MPEG audio, AAC, AC-3, E-AC-3
+    Audio,
+    /// Anything else, such as subtitles or private data
+    Other,
+}
+
+impl fmt::Display for StreamKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StreamKind::Video => write!(f, "video"),
+            StreamKind::Audio => write!(f, "audio"),
+            StreamKind::Other => write!(f, "other"),
+        }
+    }
+}
+
+impl ElementaryStream {
+    /// Kind of the stream, from its stream type.
+    pub fn kind(&self) -> StreamKind {
+        match self.stream_type {
+            0x01 | 0x02 | 0x10 | 0x1b | 0x24 | 0x42 | 0xea => StreamKind::Video,
+            0x03 | 0x04 | 0x0f | 0x11 | 0x81 | 0x87 => StreamKind::Audio,
+            _ => StreamKind::Other,
+        }
+    }
+}
+
+/// Program map table: the elementary streams of a program.
+pub struct Pmt {
+    /// Number of the program in the PAT
+    pub program_number: u16,
+    /// Version of the PMT
+    pub version: u8,
+    /// PID of the packets carrying the PCR of the program
+    pub pcr_pid: u16,
+    /// Elementary streams of the program
+    pub streams: Vec<ElementaryStream>,
+}
+
+impl Pmt {
+    /// Parses the streams of a PMT section.
+    pub fn parse(section: &Section) -> anyhow::Result<Self> {
+        if section.table_id != 0x02 {
+            bail!(MtfError::InvalidSection("not a PMT"));
+        }
+
+        let body = section.body;
+        if body.len() < 4 {
+            bail!(MtfError::InvalidSection("PMT too short"));
+        }
+        let pcr_pid = u16::from_be_bytes([body[0], body[1]]) & 0x1fff;
+        let program_info_length = (u16::from_be_bytes([body[2], body[3]]) & 0x0fff) as usize;
+
+        let mut streams = Vec::new();
+        let mut rest = body
+            .get(4 + program_info_length..)
+            .ok_or(MtfError::InvalidSection("invalid program info length"))?;
+        while rest.len() >= 5 {
+            let stream_type = rest[0];
+            let pid = u16::from_be_bytes([rest[1], rest[2]]) & 0x1fff;
+            let es_info_length = (u16::from_be_bytes([rest[3], rest[4]]) & 0x0fff) as usize;
+            streams.push(ElementaryStream { stream_type, pid });
+            rest = rest
+                .get(5 + es_info_length..)
+                .ok_or(MtfError::InvalidSection("invalid ES info length"))?;
+        }
+
+        Ok(Self {
+            program_number: section.table_id_extension,
+            version: section.version,
+            pcr_pid,
+            streams,
+        })
+    }
+}
+
+/// A program of the transport stream, as described by the PAT and its PMT.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Program {
+    /// Program number
+    pub number: u16,
+    /// PID of the PMT of the program
+    pub pmt_pid: u16,
+    /// Missing if the PMT of the program was not found
+    pub pcr_pid: Option<u16>,
+    /// Elementary streams of the program, empty if the PMT was not found
+    pub streams: Vec<ElementaryStream>,
+}
+
+/// Reassembles the sections carried by the packets of a PID.
+#[derive(Default)]
+struct SectionBuffer {
+    data: Vec<u8>,
+    started: bool,
+}
+
+impl SectionBuffer {
+    fn push(&mut self, is_start: bool, payload: &[u8], sections: &mut Vec<Vec<u8>>) {
+        let mut rest = payload;
+        if is_start {
+            let Some((&pointer, tail)) = payload.split_first() else {
+                return;
+            };
+            let pointer = pointer as usize;
+            if pointer > tail.len() {
+                self.data.clear();
+                self.started = false;
+                return;
+            }
+            if self.started {
+                // the end of the previous section
+                self.data.extend_from_slice(&tail[..pointer]);
+                self.take_sections(sections);
+            }
+            self.data.clear();
+            self.started = true;
+            rest = &tail[pointer..];
+        } else if !self.started {
+            return;
+        }
+
+        self.data.extend_from_slice(rest);
+        self.take_sections(sections);
+    }
+
+    fn take_sections(&mut self, sections: &mut Vec<Vec<u8>>) {
+        while self.started {
+            if self.data.first() == Some(&0xff) {
+                // stuffing up to the end of the packet
+                self.data.clear();
+                self.started = false;
+            } else if self.data.len() < 3 {
+                return;
+            } else {
+                let length = 3 + (((self.data[1] & 0x0f) as usize) << 8 | self.data[2] as usize);
+                if self.data.len() < length {
+                    return;
+                }
+                sections.push(self.data.drain(..length).collect());
+                self.started = !self.data.is_empty();
+            }
+        }
+    }
+}
+
+/// Tracks the program structure of a transport stream from its PAT and PMT
+/// sections. Sections failing the CRC check are ignored.
+#[derive(Default)]
+pub struct ProgramMap {
+    pat_version: Option<u8>,
+    /// PMT PID by program number
+    pmt_pids: BTreeMap<u16, u16>,
+    pmts: BTreeMap<u16, Pmt>,
+    buffers: HashMap<u16, SectionBuffer>,
+    invalid_sections: usize,
+}
+
+impl ProgramMap {
+    /// A map which knows `programs`, as recorded in a hash file, until the
+    /// PAT and PMTs are found again.
+    pub fn with_programs(programs: &[Program]) -> Self {
+        let mut map = Self::default();
+        for program in programs {
+            map.pmt_pids.insert(program.number, program.pmt_pid);
+            if let Some(pcr_pid) = program.pcr_pid {
+                let pmt = Pmt {
+                    program_number: program.number,
+                    version: 0,
+                    pcr_pid,
+                    streams: program.streams.clone(),
+                };
+                map.pmts.insert(program.number, pmt);
+            }
+        }
+        map
+    }
+
+    /// Feeds a packet, which is ignored unless it carries a PAT or PMT.
+    pub fn push(&mut self, packet: &[u8; 188]) {
+        let Ok(header) = MpegtsHeader::new(packet) else {
+            return;
+        };
+        if header.pid != PAT_PID && !self.is_pmt_pid(header.pid) {
+            return;
+        }
+        let Some(payload) = payload(packet) else {
+            return;
+        };
+
+        let mut sections = Vec::new();
+        self.buffers
+            .entry(header.pid)
+            .or_default()
+            .push(header.is_start, payload, &mut sections);
+
+        for data in sections {
+            if self.push_section(header.pid, &data).is_err() {
+                self.invalid_sections += 1;
+            }
+        }
+    }
+
+    fn push_section(&mut self, pid: u16, data: &[u8]) -> anyhow::Result<()> {
+        let section = Section::parse(data)?;
+        if !section.current_next {
+            return Ok(());
+        }
+
+        if pid == PAT_PID {
+            let pat = Pat::parse(&section)?;
+            if self.pat_version != Some(pat.version) && section.section_number == 0 {
+                self.pat_version = Some(pat.version);
+                self.pmt_pids.clear();
+            }
+            self.pmt_pids.extend(pat.programs);
+            self.pmts
+                .retain(|number, _| self.pmt_pids.contains_key(number));
+        } else if section.table_id == 0x02 {
+            let pmt = Pmt::parse(&section)?;
+            if self.pmt_pids.get(&pmt.program_number) == Some(&pid) {
+                self.pmts.insert(pmt.program_number, pmt);
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether `pid` carries the PMT of a program of the latest PAT.
+    pub fn is_pmt_pid(&self, pid: u16) -> bool {
+        self.pmt_pids.values().any(|pmt_pid| *pmt_pid == pid)
+    }
+
+    /// Number of sections dropped because they were invalid.
+    pub fn invalid_sections(&self) -> usize {
+        self.invalid_sections
+    }
+
+    /// PMT of a program, if it was found.
+    pub fn pmt(&self, program_number: u16) -> Option<&Pmt> {
+        self.pmts.get(&program_number)
+    }
+
+    /// The first video stream of the programs, if any.
+    pub fn video_stream(&self) -> Option<&ElementaryStream> {
+        self.pmts
+            .values()
+            .flat_map(|pmt| &pmt.streams)
+            .find(|stream| stream.kind() == StreamKind::Video)
+    }
+
+    /// The programs of the latest PAT, with the streams of their latest PMT.
+    pub fn programs(&self) -> Vec<Program> {
+        self.pmt_pids
+            .iter()
+            .map(|(number, pmt_pid)| {
+                let pmt = self.pmts.get(number);
+                Program {
+                    number: *number,
+                    pmt_pid: *pmt_pid,
+                    pcr_pid: pmt.map(|pmt| pmt.pcr_pid),
+                    streams: pmt.map(|pmt| pmt.streams.clone()).unwrap_or_default(),
+                }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::packet;
+
+    /// A long section with its CRC.
+    fn section(table_id: u8, extension: u16, version: u8, body: &[u8]) -> Vec<u8> {
+        let length = 5 + body.len() + 4;
+        let mut data = vec![
+            table_id,
+            0xb0 | (length >> 8) as u8,
+            length as u8,
+            (extension >> 8) as u8,
+            extension as u8,
+            0xc1 | version << 1,
+            0,
+            0,
+        ];
+        data.extend_from_slice(body);
+        let crc = crc32(&data);
+        data.extend_from_slice(&crc.to_be_bytes());
+        data
+    }
+
+    fn pat(version: u8, programs: &[(u16, u16)]) -> Vec<u8> {
+        let body: Vec<u8> = programs
+            .iter()
+            .flat_map(|(number, pid)| [number.to_be_bytes(), (0xe000 | pid).to_be_bytes()])
+            .flatten()
+            .collect();
+        section(0x00, 1, version, &body)
+    }
+
+    /// A PMT with a descriptor for the program and each stream.
+    fn pmt(number: u16, pcr_pid: u16, streams: &[(u8, u16)]) -> Vec<u8> {
+        let mut body = Vec::new();
+        body.extend_from_slice(&(0xe000 | pcr_pid).to_be_bytes());
+        body.extend_from_slice(&[0xf0, 3, 0x05, 1, 0x41]);
+        for (stream_type, pid) in streams {
+            body.push(*stream_type);
+            body.extend_from_slice(&(0xe000 | pid).to_be_bytes());
+            body.extend_from_slice(&[0xf0, 2, 0x0a, 0]);
+        }
+        section(0x02, number, 0, &body)
+    }
+
+    /// Packets of `pid` carrying `data` after a pointer field, stuffed with
+    /// 0xff.
+    fn packets(pid: u16, data: &[u8]) -> Vec<[u8; 188]> {
+        let payload = [&[0][..], data].concat();
+        payload
+            .chunks(184)
+            .enumerate()
+            .map(|(i, chunk)| {
+                let mut packet = packet(pid, i == 0, 0xff);
+                packet[4..4 + chunk.len()].copy_from_slice(chunk);
+                packet
+            })
+            .collect()
+    }
+
+    fn push_all(map: &mut ProgramMap, pid: u16, data: &[u8]) {
+        for packet in packets(pid, data) {
+            map.push(&packet);
+        }
+    }
+
+    #[test]
+    fn parses_a_pat_and_a_pmt() {
+        let data = pat(3, &[(0, 0x10), (1, 0x1000), (2, 0x1001)]);
+        let pat = Pat::parse(&Section::parse(&data).unwrap()).unwrap();
+        assert_eq!(pat.transport_stream_id, 1);
+        assert_eq!(pat.version, 3);
+        // without the network PID of program 0
+        assert_eq!(pat.programs, vec![(1, 0x1000), (2, 0x1001)]);
+
+        let data = pmt(2, 0x100, &[(0x1b, 0x100), (0x0f, 0x101), (0x06, 0x102)]);
+        let pmt = Pmt::parse(&Section::parse(&data).unwrap()).unwrap();
+        assert_eq!(pmt.program_number, 2);
+        assert_eq!(pmt.pcr_pid, 0x100);
+        let kinds: Vec<_> = pmt.streams.iter().map(|s| (s.pid, s.kind())).collect();
+        assert_eq!(
+            kinds,
+            vec![
+                (0x100, StreamKind::Video),
+                (0x101, StreamKind::Audio),
+                (0x102, StreamKind::Other)
+            ]
+        );
+
+        // a PMT is not a PAT
+        let section = Section::parse(&data).unwrap();
+        assert!(Pat::parse(&section).is_err());
+    }
+
+    #[test]
+    fn rejects_sections_failing_the_crc() {
+        let mut data = pat(0, &[(1, 0x1000)]);
+        data[9] ^= 0x01;
+        let error = Section::parse(&data).err().unwrap();
+        assert!(matches!(
+            error.downcast_ref(),
+            Some(MtfError::InvalidSection("CRC mismatch"))
+        ));
+
+        let mut map = ProgramMap::default();
+        push_all(&mut map, PAT_PID, &data);
+        assert_eq!(map.invalid_sections(), 1);
+        assert!(map.programs().is_empty());
+    }
+
+    #[test]
+    fn reassembles_sections_spanning_several_packets() {
+        let streams: Vec<(u8, u16)> = (0..60).map(|i| (0x0f, 0x200 + i)).collect();
+        let data = pmt(1, 0x200, &streams);
+        assert!(packets(0x1000, &data).len() > 2);
+
+        let mut map = ProgramMap::default();
+        push_all(&mut map, PAT_PID, &pat(0, &[(1, 0x1000)]));
+        push_all(&mut map, 0x1000, &data);
+        assert_eq!(map.invalid_sections(), 0);
+        assert_eq!(map.pmt(1).unwrap().streams.len(), 60);
+    }
+
+    #[test]
+    fn ends_a_section_in_the_packet_starting_the_next() {
+        let first = pmt(1, 0x100, &[(0x1b, 0x100); 40]);
+        let second = pmt(1, 0x100, &[(0x0f, 0x101)]);
+
+        // the first packet holds the start of the first section, the second
+        // its end before the pointed second section
+        let mut start = packet(0x1000, true, 0xff);
+        start[4] = 0;
+        start[5..].copy_from_slice(&first[..183]);
+        let rest = &first[183..];
+        let mut end = packet(0x1000, true, 0xff);
+        end[4] = rest.len() as u8;
+        end[5..5 + rest.len()].copy_from_slice(rest);
+        end[5 + rest.len()..5 + rest.len() + second.len()].copy_from_slice(&second);
+
+        let mut buffer = SectionBuffer::default();
+        let mut sections = Vec::new();
+        for packet in [start, end] {
+            buffer.push(true, payload(&packet).unwrap(), &mut sections);
+        }
+        assert_eq!(sections, vec![first, second]);
+    }
+
+    #[test]
+    fn follows_pat_version_changes() {
+        let mut map = ProgramMap::default();
+        push_all(&mut map, PAT_PID, &pat(0, &[(1, 0x1000)]));
+        push_all(&mut map, 0x1000, &pmt(1, 0x100, &[(0x1b, 0x100)]));
+        assert!(map.is_pmt_pid(0x1000));
+        assert_eq!(map.video_stream().unwrap().pid, 0x100);
+
+        // the same version again keeps the programs
+        push_all(&mut map, PAT_PID, &pat(0, &[(1, 0x1000)]));
+        assert!(map.pmt(1).is_some());
+
+        push_all(&mut map, PAT_PID, &pat(1, &[(2, 0x1001)]));
+        assert!(!map.is_pmt_pid(0x1000));
+        assert!(map.is_pmt_pid(0x1001));
+        assert!(map.pmt(1).is_none());
+        let programs = map.programs();
+        assert_eq!(programs.len(), 1);
+        assert_eq!((programs[0].number, programs[0].pcr_pid), (2, None));
+
+        // the PMT of the removed program is no longer followed
+        push_all(&mut map, 0x1000, &pmt(1, 0x100, &[(0x1b, 0x100)]));
+        assert!(map.pmt(1).is_none());
+        push_all(&mut map, 0x1001, &pmt(2, 0x101, &[(0x24, 0x101)]));
+        assert_eq!(map.video_stream().unwrap().pid, 0x101);
+    }
+}
@@ -0,0 +1,1036 @@
+//! Splitting videos into segments and hashing them.
+
+use crate::{
+    error::MtfError,
+    format::{detect_format, read_binary, Format},
+    hasher::Algorithm,
+    header::{mask_volatile, payload, random_access_indicator, MpegtsHeader, NULL_PID},
+    mmap::{Mmap, MmapScanner},
+    nal::{Codec, NalScanner},
+    packet::{ByteRange, Packet, PacketReader, PacketSource, TS_PACKET_SIZE},
+    pes::{pes_payload, pts},
+    psi::{Program, ProgramMap, PAT_PID},
+    sketch,
+    timestamp::{Clock, PtsRange, PCR_HZ},
+};
+use anyhow::{anyhow, bail};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    hash::Hasher,
+    io::{BufReader, Read, Seek, SeekFrom},
+    ops::Index,
+    path::{Path, PathBuf},
+};
+
+/// What part of the packets goes into a segment hash.
+#[derive(Serialize, Deserialize, ValueEnum, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Fingerprint {
+    /// Only the sequence of PIDs. Different segments with the same packet
+    /// layout collide, so matches are verified by comparing bytes.
+    #[default]
+    Layout,
+    /// The full packets, so a matching hash means matching content.
+    Content,
+}
+
+/// Where segments start.
+#[derive(Serialize, Deserialize, ValueEnum, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum SplitOn {
+    /// At each PAT
+    #[default]
+    Pat,
+    /// At each video packet with the random access indicator set in its
+    /// adaptation field
+    Rai,
+    /// At each video PES packet starting with an H.264 IDR or H.265 IRAP
+    /// picture. The random access indicator is used for other codecs.
+    Idr,
+    /// Every `split_interval` packets
+    Packets,
+    /// At the first packet after every `split_interval` bytes
+    Bytes,
+    /// At the first packet after every `split_interval` seconds of PCR, or
+    /// PTS if the video has no PCR
+    Seconds,
+}
+
+impl SplitOn {
+    /// Whether segments are split at fixed intervals rather than at
+    /// boundaries found in the video.
+    pub fn is_fixed(self) -> bool {
+        matches!(self, SplitOn::Packets | SplitOn::Bytes | SplitOn::Seconds)
+    }
+
+    /// Whether segments start at keyframes.
+    pub fn is_keyframe(self) -> bool {
+        matches!(self, SplitOn::Rai | SplitOn::Idr)
+    }
+}
+
+/// How a video is split into segments and hashed.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct HashOptions {
+    /// Hash algorithm of the segment hashes
+    pub algorithm: Algorithm,
+    /// What part of the packets goes into segment hashes
+    pub fingerprint: Fingerprint,
+    /// Ignore continuity counters and PCR in content fingerprints
+    pub exclude_volatile: bool,
+    /// Packet size of the video, detected if `None`
+    pub packet_size: Option<usize>,
+    /// Where segments start
+    pub split_on: SplitOn,
+    /// Interval for fixed interval splits, in packets, bytes or seconds
+    pub split_interval: Option<f64>,
+    /// Record a sketch of the packet payloads of each segment, for fuzzy
+    /// matching
+    pub sketch: bool,
+    /// PIDs left out of segment hashes
+    pub ignore_pids: Vec<u16>,
+    /// Hash only the packets of this PID
+    pub only_pid: Option<u16>,
+    /// Hash null packets, whose number depends on the muxer
+    pub keep_null: bool,
+    /// Record a hash of the packet payloads of each PID in each segment, to
+    /// match some streams only
+    pub per_stream: bool,
+}
+
+impl HashOptions {
+    /// Whether the packets of `pid` go into segment hashes. Packets of other
+    /// PIDs still split segments and give their times.
+    pub fn hashes_pid(&self, pid: u16) -> bool {
+        match self.only_pid {
+            Some(only_pid) => pid == only_pid,
+            None => (pid != NULL_PID || self.keep_null) && !self.ignore_pids.contains(&pid),
+        }
+    }
+}
+
+/// A run of packets starting at a segment boundary, up to the next one.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TsSegment {
+    /// Hash of the packets of the segment
+    pub hash: u64,
+    /// Byte offset of the first packet in the video
+    pub offset: u64,
+    /// Seconds from the start of the video to the segment, from the PCR, or
+    /// the PTS if there is no PCR
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub start: Option<f64>,
+    /// Length of the segment in seconds
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub duration: Option<f64>,
+    /// Lowest PTS of the video stream in the segment, or of the first stream
+    /// with timestamps if there is no video. It is higher than `last_pts`
+    /// when the PTS wraps around within the segment.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub first_pts: Option<u64>,
+    /// Highest PTS of the same stream as `first_pts`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_pts: Option<u64>,
+    /// MinHash sketch of the hashes of the packet payloads, see
+    /// [`crate::sketch`]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub sketch: Vec<u64>,
+    /// Hash of the packet payloads of each PID, by PID
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub streams: BTreeMap<u16, u64>,
+}
+
+/// Version of the segment hashing scheme. Bump it whenever the bytes fed into
+/// the hasher change, so stale hash files are rejected instead of silently
+/// failing to match.
+///
+/// 2: null packets are no longer hashed unless `keep_null` is set.
+/// 3: packets before the first segment are no longer hashed into it.
+pub const HASH_VERSION: u32 = 3;
+
+/// The segment index of a video, as written by `mtf hash`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct HashFile {
+    /// Hashing scheme version, missing (0) in files produced before versioning
+    #[serde(default)]
+    pub version: u32,
+    /// See [`HashOptions::algorithm`]
+    #[serde(default)]
+    pub algorithm: Algorithm,
+    /// See [`HashOptions::fingerprint`]
+    #[serde(default)]
+    pub fingerprint: Fingerprint,
+    /// See [`HashOptions::exclude_volatile`]
+    #[serde(default)]
+    pub exclude_volatile: bool,
+    /// Packet size of the video, detected if it was not given
+    #[serde(default = "default_packet_size")]
+    pub packet_size: usize,
+    /// See [`HashOptions::split_on`]
+    #[serde(default)]
+    pub split_on: SplitOn,
+    /// See [`HashOptions::split_interval`]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub split_interval: Option<f64>,
+    /// Whether segments have a sketch
+    #[serde(default)]
+    pub sketch: bool,
+    /// See [`HashOptions::ignore_pids`]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub ignore_pids: Vec<u16>,
+    /// See [`HashOptions::only_pid`]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub only_pid: Option<u16>,
+    /// See [`HashOptions::keep_null`]
+    #[serde(default)]
+    pub keep_null: bool,
+    /// Whether segments have per-stream hashes
+    #[serde(default)]
+    pub per_stream: bool,
+    /// The hashed video
+    pub file: PathBuf,
+    /// Programs of the video, as of its last PAT and PMTs
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub programs: Vec<Program>,
+    /// Segments of the video, in order
+    pub segments: Vec<TsSegment>,
+    /// Byte ranges of the video which are not part of any packet
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub skipped: Vec<ByteRange>,
+    /// Number of PSI sections dropped because they were invalid, in the data
+    /// read by the last [`HashFile::new`] or [`HashFile::append`], not saved
+    #[serde(skip)]
+    pub invalid_sections: usize,
+}
+
+fn default_packet_size() -> usize {
+    TS_PACKET_SIZE
+}
+
+impl HashFile {
+    /// Hashes `video` into a new hash file.
+    pub fn new<P>(video: P, options: HashOptions) -> anyhow::Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        let video = video.as_ref();
+        Self::from_reader(File::open(video)?, video, options)
+    }
+
+    /// Hashes a video read from `reader`, such as a pipe, into a new hash
+    /// file recording `video` as its path.
+    pub fn from_reader<R, P>(reader: R, video: P, options: HashOptions) -> anyhow::Result<Self>
+    where
+        R: Read,
+        P: AsRef<Path>,
+    {
+        let mut reader = PacketReader::new(reader, options.packet_size)?;
+        let mut programs = ProgramMap::default();
+        let segments = hash_packets(&mut reader, options.clone(), &mut programs)?;
+        Ok(Self::hashed(
+            video.as_ref(),
+            options,
+            reader.packet_size(),
+            programs,
+            segments,
+            reader.skipped(),
+        ))
+    }
+
+    /// Hashes `video` like [`HashFile::new`], scanning its packets in a
+    /// memory mapping with a [`MmapScanner`], which is faster on large
+    /// files.
+    ///
+    /// # Safety
+    ///
+    /// The video must not be truncated nor modified meanwhile, see
+    /// [`Mmap::map`].
+    pub unsafe fn new_mapped<P>(video: P, options: HashOptions) -> anyhow::Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        let video = video.as_ref();
+        // SAFETY: the caller upholds the same contract
+        let map = unsafe { Mmap::open(video)? };
+        let mut scanner = MmapScanner::new(&map, options.packet_size)?;
+        let mut programs = ProgramMap::default();
+        let segments = hash_packets(&mut scanner, options.clone(), &mut programs)?;
+        Ok(Self::hashed(
+            video,
+            options,
+            scanner.packet_size(),
+            programs,
+            segments,
+            scanner.skipped(),
+        ))
+    }
+
+    fn hashed(
+        video: &Path,
+        options: HashOptions,
+        packet_size: usize,
+        programs: ProgramMap,
+        segments: Vec<TsSegment>,
+        skipped: &[ByteRange],
+    ) -> Self {
+        Self {
+            version: HASH_VERSION,
+            algorithm: options.algorithm,
+            fingerprint: options.fingerprint,
+            exclude_volatile: options.exclude_volatile,
+            packet_size,
+            split_on: options.split_on,
+            split_interval: options.split_interval,
+            sketch: options.sketch,
+            ignore_pids: options.ignore_pids,
+            only_pid: options.only_pid,
+            keep_null: options.keep_null,
+            per_stream: options.per_stream,
+            file: video.to_path_buf(),
+            programs: programs.programs(),
+            segments,
+            skipped: skipped.to_vec(),
+            invalid_sections: programs.invalid_sections(),
+        }
+    }
+
+    /// Loads a hash file in any [`Format`], rejecting unsupported hashing
+    /// scheme versions.
+    pub fn load<P>(path: P) -> anyhow::Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        let mut reader = BufReader::new(File::open(path)?);
+        let hashes: HashFile = match detect_format(&mut reader)? {
+            Format::Json => serde_json::from_reader(reader)?,
+            Format::Binary => read_binary(reader)?,
+        };
+        if hashes.version != HASH_VERSION {
+            bail!(MtfError::HashVersionMismatch {
+                found: hashes.version,
+                expected: HASH_VERSION,
+            });
+        }
+        Ok(hashes)
+    }
+
+    /// Hashes the data appended to the video since the hash file was made,
+    /// returning the number of segments added.
+    ///
+    /// The last segment may have been cut short by the end of the video, so
+    /// hashing resumes from the segment before it, whose hash must be
+    /// unchanged. Changes before that segment are not detected, nor changes
+    /// which keep the hash, such as payload changes with layout fingerprints.
+    pub fn append(&mut self) -> anyhow::Result<usize> {
+        let count = self.len();
+        let resume = count.saturating_sub(2);
+        let Some(first) = self.segments.get(resume).cloned() else {
+            bail!("the hash file has no segments");
+        };
+
+        let mut file = File::open(&self.file)?;
+        file.seek(SeekFrom::Start(first.offset))?;
+        let mut reader = PacketReader::new(file, Some(self.packet_size))?.starting_at(first.offset);
+        let mut programs = ProgramMap::with_programs(&self.programs);
+        // segments split every few seconds start at a clock reference, so
+        // resuming the clock from there keeps the boundaries of the first
+        // hash
+        let elapsed = match self.split_on {
+            SplitOn::Seconds => first
+                .start
+                .map_or(0, |start| (start * PCR_HZ as f64).round() as u64),
+            _ => 0,
+        };
+        let mut segments = hash_packets_at(&mut reader, self.options(), &mut programs, elapsed)?;
+
+        if resume + 1 < count
+            && (segments[0].offset != first.offset || segments[0].hash != first.hash)
+        {
+            bail!(
+                "{} changed since it was hashed, segment at {} differs, hash it again",
+                self.file.display(),
+                first.offset
+            );
+        }
+
+        if resume + segments.len() < count {
+            bail!(
+                "{} is shorter than when it was hashed, hash it again",
+                self.file.display()
+            );
+        }
+
+        // the same goes for a clock which had not started yet, otherwise the
+        // clock restarts at the first PCR after the resumed segment, so times
+        // continue from the end of the verified segment, whose times are
+        // kept, or from the start of the only segment
+        let (kept, base) = if self.split_on == SplitOn::Seconds || first.start.is_none() {
+            (0, None)
+        } else if resume + 1 < count {
+            let end = first
+                .start
+                .zip(first.duration)
+                .map(|(start, duration)| start + duration);
+            (
+                1,
+                end.zip(segments[1].start).map(|(end, start)| end - start),
+            )
+        } else {
+            (0, first.start)
+        };
+        if kept == 1 {
+            segments[0] = first.clone();
+        }
+        for segment in &mut segments[kept..] {
+            segment.start = segment.start.map(|start| start + base.unwrap_or_default());
+        }
+
+        self.segments.truncate(resume);
+        self.segments.extend(segments);
+        self.programs = programs.programs();
+        self.invalid_sections = programs.invalid_sections();
+        self.skipped.retain(|range| range.end <= first.offset);
+        self.skipped.extend_from_slice(reader.skipped());
+        Ok(self.len() - count)
+    }
+
+    /// The options the video was hashed with.
+    pub fn options(&self) -> HashOptions {
+        HashOptions {
+            algorithm: self.algorithm,
+            fingerprint: self.fingerprint,
+            exclude_volatile: self.exclude_volatile,
+            packet_size: Some(self.packet_size),
+            split_on: self.split_on,
+            split_interval: self.split_interval,
+            sketch: self.sketch,
+            ignore_pids: self.ignore_pids.clone(),
+            only_pid: self.only_pid,
+            keep_null: self.keep_null,
+            per_stream: self.per_stream,
+        }
+    }
+
+    /// The options to hash a query video the same way as this file, except
+    /// for the packet size which is detected, as a segment cut from an M2TS
+    /// recording may be a plain transport stream for instance.
+    pub fn query_options(&self) -> HashOptions {
+        HashOptions {
+            packet_size: None,
+            ..self.options()
+        }
+    }
+
+    /// Number of segments.
+    pub fn len(&self) -> usize {
+        self.segments.len()
+    }
+
+    /// Whether there is no segment.
+    pub fn is_empty(&self) -> bool {
+        self.segments.is_empty()
+    }
+
+    /// The segments, in order.
+    pub fn iter(&self) -> std::slice::Iter<'_, TsSegment> {
+        self.segments.iter()
+    }
+
+    /// Byte offsets of the run of `count` segments starting at `index`: its
+    /// start, and its end or `None` if it ends with the video.
+    pub fn offsets(&self, index: usize, count: usize) -> (u64, Option<u64>) {
+        (
+            self.segments[index].offset,
+            self.segments
+                .get(index + count)
+                .map(|segment| segment.offset),
+        )
+    }
+}
+
+impl Index<usize> for HashFile {
+    type Output = TsSegment;
+
+    fn index(&self, index: usize) -> &Self::Output {
+        &self.segments[index]
+    }
+}
+
+/// Splits `video` into segments and hashes them.
+pub fn do_hash<P>(video: P, options: HashOptions) -> anyhow::Result<Vec<TsSegment>>
+where
+    P: AsRef<Path>,
+{
+    hash_packets(
+        &mut PacketReader::open(video, options.packet_size)?,
+        options,
+        &mut ProgramMap::default(),
+    )
+}
+
+/// Packets kept while looking for the first picture of a video PES packet,
+/// after which it is assumed not to be a keyframe.
+const MAX_PENDING_PACKETS: usize = 64;
+
+/// Splits the packets of `reader` into segments and hashes them, collecting
+/// the program structure into `programs`.
+pub fn hash_packets<S>(
+    reader: &mut S,
+    options: HashOptions,
+    programs: &mut ProgramMap,
+) -> anyhow::Result<Vec<TsSegment>>
+where
+    S: PacketSource,
+{
+    hash_packets_at(reader, options, programs, 0)
+}
+
+/// Hashes packets like [`hash_packets`], with a clock starting at
+/// `elapsed`, in 27 MHz units, for a reader resuming at the start of a
+/// segment which started then.
+fn hash_packets_at<S>(
+    reader: &mut S,
+    options: HashOptions,
+    programs: &mut ProgramMap,
+    elapsed: u64,
+) -> anyhow::Result<Vec<TsSegment>>
+where
+    S: PacketSource,
+{
+    let split_on = options.split_on;
+    let interval = options.split_interval;
+    let mut builder = SegmentBuilder::new(options);
+    builder.clock = Clock::starting_at(elapsed);
+
+    // fixed intervals, in packets, bytes or 27 MHz ticks
+    let interval = match (split_on, interval) {
+        (SplitOn::Packets | SplitOn::Bytes, Some(interval)) if interval >= 1.0 => {
+            if interval.fract() != 0.0 {
+                bail!(
+                    "the interval to split on {split_on:?} must be a whole number, not {interval}"
+                )
+            }
+            interval as u64
+        }
+        (SplitOn::Seconds, Some(interval)) if interval > 0.0 => {
+            (interval * PCR_HZ as f64).max(1.0) as u64
+        }
+        (split_on, _) if split_on.is_fixed() => {
+            bail!("a positive split interval is required to split on {split_on:?}")
+        }
+        _ => 0,
+    };
+    let mut next_boundary = 0;
+    let mut packet_index = 0;
+
+    // with `SplitOn::Idr`, packets are held back from the start of a video
+    // PES packet until its first picture tells whether a segment starts there
+    let mut pending: Vec<Packet> = Vec::new();
+    let mut scanner: Option<NalScanner> = None;
+
+    while let Some(packet) = reader.next_packet_ref()? {
+        programs.push(packet.data);
+        builder.clock.push(packet.data);
+
+        let header = MpegtsHeader::new(packet.data)?;
+        let video = programs
+            .video_stream()
+            .map(|stream| (stream.pid, stream.stream_type));
+        let is_video = video.is_some_and(|(pid, _)| pid == header.pid);
+        if let Some((pid, _)) = video {
+            builder.pts_pid = Some(pid);
+        }
+
+        match split_on {
+            SplitOn::Pat => {
+                if header.pid == PAT_PID && header.is_start {
+                    builder.split(packet.offset);
+                }
+            }
+            SplitOn::Rai => {
+                // any PID can be a keyframe until the video stream is known
+                if (is_video || video.is_none()) && random_access_indicator(packet.data) {
+                    builder.split(packet.offset);
+                }
+            }
+            SplitOn::Idr => {
+                if is_video && header.is_start {
+                    builder.write_all(pending.drain(..))?;
+                    scanner = None;
+                    match video.and_then(|(_, stream_type)| Codec::from_stream_type(stream_type)) {
+                        Some(codec) => scanner = Some(NalScanner::new(codec)),
+                        // no NAL units to look at for other codecs
+                        None if random_access_indicator(packet.data) => {
+                            builder.split(packet.offset)
+                        }
+                        None => {}
+                    }
+                }
+
+                if let Some(nal_scanner) = &mut scanner {
+                    let data = if !is_video {
+                        None
+                    } else if header.is_start {
+                        payload(packet.data).and_then(pes_payload)
+                    } else {
+                        payload(packet.data)
+                    };
+                    let keyframe = data.and_then(|data| nal_scanner.push(data));
+
+                    // only held back packets are copied
+                    pending.push(packet.to_packet());
+                    if keyframe == Some(true) {
+                        builder.split(pending[0].offset);
+                    }
+                    if keyframe.is_some() || pending.len() >= MAX_PENDING_PACKETS {
+                        builder.write_all(pending.drain(..))?;
+                        scanner = None;
+                    }
+                    continue;
+                }
+            }
+            SplitOn::Packets | SplitOn::Bytes | SplitOn::Seconds => {
+                let position = match split_on {
+                    SplitOn::Packets => packet_index,
+                    SplitOn::Bytes => packet.offset,
+                    _ => builder.clock.elapsed(),
+                };
+                if position >= next_boundary {
+                    builder.split(packet.offset);
+                    next_boundary = (position / interval + 1) * interval;
+                }
+                packet_index += 1;
+            }
+        }
+
+        builder.write(packet.data)?;
+    }
+    builder.write_all(pending.drain(..))?;
+
+    builder.finish().ok_or_else(|| match split_on {
+        SplitOn::Pat => MtfError::NoPatFound.into(),
+        SplitOn::Rai | SplitOn::Idr => MtfError::NoKeyframeFound.into(),
+        // the first packet always starts a segment
+        SplitOn::Packets | SplitOn::Bytes | SplitOn::Seconds => anyhow!("no packet found"),
+    })
+}
+
+/// Feeds the fingerprint of a packet of `pid` into the hash of its segment.
+pub(crate) fn fingerprint_packet(
+    options: &HashOptions,
+    hasher: &mut dyn Hasher,
+    data: &[u8; TS_PACKET_SIZE],
+    pid: u16,
+) {
+    match options.fingerprint {
+        Fingerprint::Layout => hasher.write(&pid.to_be_bytes()),
+        Fingerprint::Content if options.exclude_volatile => {
+            let mut data = *data;
+            mask_volatile(&mut data);
+            hasher.write(&data);
+        }
+        Fingerprint::Content => hasher.write(data),
+    }
+}
+
+/// Hashes packets into segments.
+struct SegmentBuilder {
+    options: HashOptions,
+    hasher: Box<dyn Hasher>,
+    /// Sketch of the current segment, filled like `hasher`
+    sketch: Vec<u64>,
+    /// Hashers of the payloads of each PID in the current segment
+    stream_hashers: BTreeMap<u16, Box<dyn Hasher>>,
+    /// The current segment, `None` before the first one
+    current: Option<TsSegment>,
+    segments: Vec<TsSegment>,
+    /// Time of the packets, as they are read
+    clock: Clock,
+    /// Clock time at the start of the current segment
+    start_time: u64,
+    /// PID whose PTS are recorded, the first one with a PTS if `None`
+    pts_pid: Option<u16>,
+    /// PTS range of `pts_pid` in the current segment
+    pts_range: Option<PtsRange>,
+}
+
+impl SegmentBuilder {
+    fn new(options: HashOptions) -> Self {
+        Self {
+            hasher: options.algorithm.hasher(),
+            options,
+            sketch: Vec::new(),
+            stream_hashers: BTreeMap::new(),
+            current: None,
+            segments: Vec::new(),
+            clock: Clock::default(),
+            start_time: 0,
+            pts_pid: None,
+            pts_range: None,
+        }
+    }
+
+    /// Ends the current segment and starts a new one at `offset`.
+    fn split(&mut self, offset: u64) {
+        self.end_segment();
+        self.start_time = self.clock.elapsed();
+        self.current = Some(TsSegment {
+            hash: 0,
+            offset,
+            start: Some(self.start_time as f64 / PCR_HZ as f64),
+            duration: None,
+            first_pts: None,
+            last_pts: None,
+            sketch: Vec::new(),
+            streams: BTreeMap::new(),
+        });
+    }
+
+    fn end_segment(&mut self) {
+        if let Some(mut segment) = self.current.take() {
+            segment.hash = self.hasher.finish();
+            self.hasher = self.options.algorithm.hasher();
+            segment.sketch = std::mem::take(&mut self.sketch);
+            segment.streams = std::mem::take(&mut self.stream_hashers)
+                .into_iter()
+                .map(|(pid, hasher)| (pid, hasher.finish()))
+                .collect();
+            let duration = self.clock.elapsed() - self.start_time;
+            segment.duration = Some(duration as f64 / PCR_HZ as f64);
+            if let Some(range) = self.pts_range.take() {
+                segment.first_pts = Some(range.start());
+                segment.last_pts = Some(range.end());
+            }
+            self.segments.push(segment);
+        }
+    }
+
+    fn write(&mut self, data: &[u8; TS_PACKET_SIZE]) -> anyhow::Result<()> {
+        // packets before the first segment belong to none, so that hashing
+        // from the start of a segment gives the same hash
+        if self.current.is_none() {
+            return Ok(());
+        }
+        let header = MpegtsHeader::new(data)?;
+
+        if header.is_start {
+            if let Some(pts) = payload(data).and_then(pts) {
+                if *self.pts_pid.get_or_insert(header.pid) == header.pid {
+                    match &mut self.pts_range {
+                        Some(range) => range.push(pts),
+                        None => self.pts_range = Some(PtsRange::new(pts)),
+                    }
+                }
+            }
+        }
+
+        if !self.options.hashes_pid(header.pid) {
+            return Ok(());
+        }
+
+        // payloads only, so that PIDs, continuity counters and adaptation
+        // fields may change
+        if let Some(payload) = payload(data) {
+            if self.options.sketch {
+                let mut hasher = self.options.algorithm.hasher();
+                hasher.write(payload);
+                sketch::insert(&mut self.sketch, hasher.finish());
+            }
+            if self.options.per_stream {
+                let algorithm = self.options.algorithm;
+                self.stream_hashers
+                    .entry(header.pid)
+                    .or_insert_with(|| algorithm.hasher())
+                    .write(payload);
+            }
+        }
+
+        fingerprint_packet(&self.options, &mut *self.hasher, data, header.pid);
+        Ok(())
+    }
+
+    fn write_all<I>(&mut self, packets: I) -> anyhow::Result<()>
+    where
+        I: IntoIterator<Item = Packet>,
+    {
+        for packet in packets {
+            self.write(&packet.data)?;
+        }
+        Ok(())
+    }
+
+    /// Ends the last segment, returns `None` if no segment was started.
+    fn finish(mut self) -> Option<Vec<TsSegment>> {
+        self.current.as_ref()?;
+        self.end_segment();
+
+        if !self.clock.is_running() {
+            // no PCR nor PTS, the times are meaningless
+            for segment in &mut self.segments {
+                segment.start = None;
+                segment.duration = None;
+            }
+        }
+        Some(self.segments)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{frame_start, packet, pat, pmt, timed_video, video, TempFile, VIDEO_PID};
+    use std::fs;
+
+    /// Options for each split mode, with intervals which do not fall on
+    /// PATs or keyframes.
+    fn split_modes() -> Vec<HashOptions> {
+        [
+            (SplitOn::Pat, None),
+            (SplitOn::Rai, None),
+            (SplitOn::Idr, None),
+            (SplitOn::Packets, Some(7.0)),
+            (SplitOn::Bytes, Some(2000.0)),
+            (SplitOn::Seconds, Some(0.3)),
+        ]
+        .into_iter()
+        .map(|(split_on, split_interval)| HashOptions {
+            split_on,
+            split_interval,
+            ..Default::default()
+        })
+        .collect()
+    }
+
+    fn assert_same_segments(found: &HashFile, expected: &HashFile, context: &str) {
+        assert_eq!(found.len(), expected.len(), "{context}");
+        for (a, b) in found.iter().zip(expected.iter()) {
+            assert_eq!((a.offset, a.hash), (b.offset, b.hash), "{context}");
+            assert_eq!(
+                (a.first_pts, a.last_pts),
+                (b.first_pts, b.last_pts),
+                "{context}"
+            );
+            let start = a.start.zip(b.start).map(|(a, b)| (a - b).abs());
+            assert!(start.is_some_and(|delta| delta < 1e-9), "{context}");
+        }
+    }
+
+    #[test]
+    fn appending_gives_the_same_segments_as_hashing_at_once() {
+        let data = timed_video(40, 10);
+        let file = TempFile::new(&data);
+        for options in split_modes() {
+            fs::write(file.path(), &data).unwrap();
+            let expected = HashFile::new(file.path(), options.clone()).unwrap();
+            assert!(expected.len() > 3, "{:?}", options.split_on);
+
+            for packets in (1..data.len() / TS_PACKET_SIZE).step_by(3) {
+                fs::write(file.path(), &data[..packets * TS_PACKET_SIZE]).unwrap();
+                // too short to hold a segment yet
+                let Ok(mut hashes) = HashFile::new(file.path(), options.clone()) else {
+                    continue;
+                };
+                fs::write(file.path(), &data).unwrap();
+                let context = format!("{:?} after {packets} packets", options.split_on);
+                hashes.append().expect(&context);
+                assert_same_segments(&hashes, &expected, &context);
+            }
+        }
+    }
+
+    #[test]
+    fn appending_repeatedly_gives_the_same_segments() {
+        let data = timed_video(40, 10);
+        let file = TempFile::new(&data);
+        for options in split_modes() {
+            fs::write(file.path(), &data).unwrap();
+            let expected = HashFile::new(file.path(), options.clone()).unwrap();
+
+            let mut hashes: Option<HashFile> = None;
+            for packets in (1..=data.len() / TS_PACKET_SIZE).step_by(5) {
+                fs::write(file.path(), &data[..packets * TS_PACKET_SIZE]).unwrap();
+                match &mut hashes {
+                    Some(hashes) => {
+                        hashes.append().unwrap();
+                    }
+                    None => hashes = HashFile::new(file.path(), options.clone()).ok(),
+                }
+            }
+            fs::write(file.path(), &data).unwrap();
+            let mut hashes = hashes.unwrap();
+            hashes.append().unwrap();
+            assert_same_segments(&hashes, &expected, &format!("{:?}", options.split_on));
+        }
+    }
+
+    #[test]
+    fn rejects_fractional_packet_intervals() {
+        let file = TempFile::new(&video(&[1, 2]));
+        let options = HashOptions {
+            split_on: SplitOn::Packets,
+            split_interval: Some(1.5),
+            ..Default::default()
+        };
+        let error = HashFile::new(file.path(), options).unwrap_err();
+        assert!(error.to_string().contains("whole number"), "{error}");
+    }
+
+    /// Hashes `data` split on `split_on`, in memory.
+    fn hash_data(data: &[u8], split_on: SplitOn) -> anyhow::Result<Vec<TsSegment>> {
+        let options = HashOptions {
+            split_on,
+            ..Default::default()
+        };
+        hash_with(data, options)
+    }
+
+    fn hash_with(data: &[u8], options: HashOptions) -> anyhow::Result<Vec<TsSegment>> {
+        let mut reader = PacketReader::new(std::io::Cursor::new(data), None)?;
+        hash_packets(&mut reader, options, &mut ProgramMap::default())
+    }
+
+    fn segments_of(segments: &[TsSegment]) -> Vec<(u64, u64)> {
+        segments.iter().map(|s| (s.offset, s.hash)).collect()
+    }
+
+    /// The start of a video PES packet whose picture starts in a later
+    /// packet.
+    fn late_frame_start(time: u64, keyframe: bool, fill: u8) -> [u8; TS_PACKET_SIZE] {
+        let mut packet = frame_start(time, keyframe, fill);
+        // the NAL unit after the 19-byte PES header
+        packet[26..31].fill(fill);
+        packet
+    }
+
+    #[test]
+    fn splits_on_idr_pictures_found_after_the_pes_header() {
+        let mut packets = vec![pat(), pmt()];
+        let frame = |packets: &mut Vec<[u8; TS_PACKET_SIZE]>, rest: usize, fill| {
+            packets.extend((0..rest).map(|_| packet(VIDEO_PID, false, fill)));
+        };
+
+        // the IDR slice three packets after the start of the PES packet
+        packets.push(late_frame_start(0, true, 0x30));
+        frame(&mut packets, 2, 0x30);
+        let mut idr = packet(VIDEO_PID, false, 0x30);
+        idr[4..9].copy_from_slice(&[0, 0, 0, 1, 0x65]);
+        packets.push(idr);
+        frame(&mut packets, 1, 0x30);
+
+        packets.push(frame_start(3600, false, 0x31));
+        frame(&mut packets, 1, 0x31);
+
+        // an SEI, then an IDR slice whose start code spans two packets
+        let mut start = late_frame_start(7200, true, 0x32);
+        start[26..32].copy_from_slice(&[0, 0, 0, 1, 0x06, 0x05]);
+        start[186..].fill(0);
+        packets.push(start);
+        let mut idr = packet(VIDEO_PID, false, 0x32);
+        idr[4..6].copy_from_slice(&[0x01, 0x65]);
+        packets.push(idr);
+        frame(&mut packets, 1, 0x32);
+
+        // an IDR slice too far from the start of its PES packet is missed
+        packets.push(late_frame_start(10800, false, 0x33));
+        frame(&mut packets, MAX_PENDING_PACKETS + 6, 0x33);
+        let mut idr = packet(VIDEO_PID, false, 0x33);
+        idr[4..9].copy_from_slice(&[0, 0, 0, 1, 0x65]);
+        packets.push(idr);
+
+        packets.push(frame_start(14400, true, 0x34));
+        frame(&mut packets, 3, 0x34);
+        let data: Vec<u8> = packets.concat();
+
+        let segments = hash_data(&data, SplitOn::Idr).unwrap();
+        let offsets: Vec<u64> = segments.iter().map(|s| s.offset).collect();
+        let late = 14 + MAX_PENDING_PACKETS as u64 + 6;
+        assert_eq!(offsets, vec![2 * 188, 9 * 188, late * 188]);
+
+        // the same packets, held back or not, as when splitting on the
+        // random access indicators of the same frames
+        let by_rai = segments_of(&hash_data(&data, SplitOn::Rai).unwrap());
+        assert_eq!(segments_of(&segments), by_rai);
+    }
+
+    #[test]
+    fn selects_the_pids_to_hash() {
+        let options = HashOptions::default();
+        assert!(options.hashes_pid(PAT_PID) && options.hashes_pid(VIDEO_PID));
+        assert!(!options.hashes_pid(NULL_PID));
+
+        let options = HashOptions {
+            keep_null: true,
+            ignore_pids: vec![VIDEO_PID],
+            ..Default::default()
+        };
+        assert!(options.hashes_pid(NULL_PID) && options.hashes_pid(PAT_PID));
+        assert!(!options.hashes_pid(VIDEO_PID));
+
+        let options = HashOptions {
+            only_pid: Some(VIDEO_PID),
+            keep_null: true,
+            ..Default::default()
+        };
+        assert!(options.hashes_pid(VIDEO_PID));
+        assert!(!options.hashes_pid(PAT_PID) && !options.hashes_pid(NULL_PID));
+    }
+
+    #[test]
+    fn leaves_null_and_ignored_packets_out_of_hashes() {
+        let clean = video(&[1, 2, 3]);
+        // a null packet and one of another PID after each packet
+        let noisy: Vec<u8> = clean
+            .chunks(TS_PACKET_SIZE)
+            .enumerate()
+            .flat_map(|(i, data)| {
+                [
+                    data,
+                    &packet(NULL_PID, false, 0xff),
+                    &packet(0x200, false, i as u8),
+                ]
+                .concat()
+            })
+            .collect();
+
+        let hashes = |data: &[u8], options: &HashOptions| -> Vec<u64> {
+            let segments = hash_with(data, options.clone()).unwrap();
+            segments.iter().map(|segment| segment.hash).collect()
+        };
+        let same = |options: HashOptions| hashes(&clean, &options) == hashes(&noisy, &options);
+
+        assert!(!same(HashOptions::default()));
+        for fingerprint in [Fingerprint::Layout, Fingerprint::Content] {
+            assert!(same(HashOptions {
+                fingerprint,
+                ignore_pids: vec![0x200],
+                ..Default::default()
+            }));
+            assert!(same(HashOptions {
+                fingerprint,
+                only_pid: Some(VIDEO_PID),
+                keep_null: true,
+                ..Default::default()
+            }));
+            assert!(!same(HashOptions {
+                fingerprint,
+                ignore_pids: vec![0x200],
+                keep_null: true,
+                ..Default::default()
+            }));
+        }
+    }
+
+    #[test]
+    fn finds_no_keyframe_without_idr_picture() {
+        let mut data = [pat(), pmt(), frame_start(0, false, 0x30)].concat();
+        data.extend_from_slice(&late_frame_start(3600, true, 0x31));
+        let error = hash_data(&data, SplitOn::Idr).unwrap_err();
+        assert!(matches!(
+            error.downcast_ref(),
+            Some(MtfError::NoKeyframeFound)
+        ));
+    }
+}
@@ -0,0 +1,88 @@
+//! MinHash sketches for fuzzy matching.
+
+/// Number of hashes kept in a sketch.
+pub const SKETCH_SIZE: usize = 32;
+
+/// Adds a hash to a bottom-k MinHash sketch: the [`SKETCH_SIZE`] lowest
+/// distinct hashes of a set, in ascending order.
+pub fn insert(sketch: &mut Vec<u64>, hash: u64) {
+    if sketch.len() == SKETCH_SIZE && hash >= sketch[SKETCH_SIZE - 1] {
+        return;
+    }
+    if let Err(position) = sketch.binary_search(&hash) {
+        sketch.insert(position, hash);
+        sketch.truncate(SKETCH_SIZE);
+    }
+}
+
+/// Sketch of the union of the sets of two sketches.
+pub fn merge(a: &[u64], b: &[u64]) -> Vec<u64> {
+    let mut union = a.to_vec();
+    for hash in b {
+        insert(&mut union, *hash);
+    }
+    union
+}
+
+/// Estimated Jaccard similarity of the sets of two sketches, from 0 for
+/// disjoint sets to 1 for identical ones.
+pub fn similarity(a: &[u64], b: &[u64]) -> f64 {
+    let union = merge(a, b);
+    if union.is_empty() {
+        return 0.0;
+    }
+    // a hash among the lowest of the union which is in a set is also among
+    // the lowest of that set, so the sketches are enough to test membership
+    let both = union
+        .iter()
+        .filter(|hash| a.binary_search(hash).is_ok() && b.binary_search(hash).is_ok())
+        .count();
+    both as f64 / union.len() as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sketch_of(hashes: impl IntoIterator<Item = u64>) -> Vec<u64> {
+        let mut sketch = Vec::new();
+        for hash in hashes {
+            insert(&mut sketch, hash);
+        }
+        sketch
+    }
+
+    #[test]
+    fn keeps_the_lowest_distinct_hashes() {
+        let sketch = sketch_of((0..100).rev().chain([5, 5, 7]));
+        assert_eq!(sketch, (0..SKETCH_SIZE as u64).collect::<Vec<_>>());
+
+        let sketch = sketch_of([9, 3, 9, 1]);
+        assert_eq!(sketch, vec![1, 3, 9]);
+    }
+
+    #[test]
+    fn merges_sketches_into_the_sketch_of_the_union() {
+        let a = sketch_of((0..100).step_by(2));
+        let b = sketch_of((0..100).step_by(3));
+        let union = sketch_of((0..100).filter(|i| i % 2 == 0 || i % 3 == 0));
+        assert_eq!(merge(&a, &b), union);
+    }
+
+    #[test]
+    fn estimates_the_share_of_hashes_in_common() {
+        let a = sketch_of(0..20);
+        assert_eq!(similarity(&a, &a), 1.0);
+        assert_eq!(similarity(&a, &sketch_of(20..40)), 0.0);
+        assert_eq!(similarity(&[], &[]), 0.0);
+        // 10 of the 30 hashes of the union
+        assert_eq!(similarity(&a, &sketch_of(10..30)), 10.0 / 30.0);
+
+        // large sets, estimated from their lowest hashes
+        let spread = |range: std::ops::Range<u64>| {
+            sketch_of(range.map(|i| i.wrapping_mul(0x9e3779b97f4a7c15)))
+        };
+        let score = similarity(&spread(0..1000), &spread(500..1500));
+        assert!(score > 0.15 && score < 0.55, "{score}");
+    }
+}
@@ -0,0 +1,178 @@
+//! Tiny transport streams for unit tests.
+
+use crate::{packet::TS_PACKET_SIZE, psi::crc32};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+pub const PMT_PID: u16 = 0x1000;
+pub const VIDEO_PID: u16 = 0x100;
+
+/// A packet of `pid` with a payload of `fill` bytes.
+pub fn packet(pid: u16, start: bool, fill: u8) -> [u8; TS_PACKET_SIZE] {
+    let mut packet = [fill; TS_PACKET_SIZE];
+    packet[0] = 0x47;
+    packet[1] = if start { 0x40 } else { 0 } | (pid >> 8) as u8;
+    packet[2] = pid as u8;
+    packet[3] = 0x10;
+    packet
+}
+
+/// A packet holding a PSI section, with its CRC.
+fn psi(pid: u16, table_id: u8, body: &[u8]) -> [u8; TS_PACKET_SIZE] {
+    let length = 5 + body.len() + 4;
+    let mut section = vec![
+        table_id,
+        0xb0 | (length >> 8) as u8,
+        length as u8,
+        0,
+        1,
+        0xc1,
+        0,
+        0,
+    ];
+    section.extend_from_slice(body);
+    let crc = crc32(&section);
+    section.extend_from_slice(&crc.to_be_bytes());
+
+    let mut packet = packet(pid, true, 0xff);
+    packet[4] = 0;
+    packet[5..5 + section.len()].copy_from_slice(&section);
+    packet
+}
+
+pub fn pat() -> [u8; TS_PACKET_SIZE] {
+    psi(0, 0, &[0, 1, 0xe0 | (PMT_PID >> 8) as u8, PMT_PID as u8])
+}
+
+pub fn pmt() -> [u8; TS_PACKET_SIZE] {
+    pmt_with(&[(0x1b, VIDEO_PID)])
+}
+
+/// A PMT of the given `(stream_type, pid)` streams, the PCR on the first.
+pub fn pmt_with(streams: &[(u8, u16)]) -> [u8; TS_PACKET_SIZE] {
+    let pcr_pid = streams[0].1;
+    let mut body = vec![0xe0 | (pcr_pid >> 8) as u8, pcr_pid as u8, 0xf0, 0];
+    for (stream_type, pid) in streams {
+        body.extend_from_slice(&[*stream_type, 0xe0 | (pid >> 8) as u8, *pid as u8, 0xf0, 0]);
+    }
+    psi(PMT_PID, 2, &body)
+}
+
+/// A segment of 5 packets, PAT, PMT and 3 video packets whose payloads are
+/// `fill` bytes, so segments with different fills have the same PID layout.
+pub fn segment(fill: u8) -> Vec<u8> {
+    let mut data = Vec::new();
+    data.extend_from_slice(&pat());
+    data.extend_from_slice(&pmt());
+    for _ in 0..3 {
+        data.extend_from_slice(&packet(VIDEO_PID, false, fill));
+    }
+    data
+}
+
+/// Concatenated segments with the given payload fills.
+pub fn video(fills: &[u8]) -> Vec<u8> {
+    fills.iter().flat_map(|fill| segment(*fill)).collect()
+}
+
+/// The first packet of a frame of H.264 video: a PCR, the random access
+/// indicator on keyframes, and a PES header with the PTS before an IDR or
+/// non-IDR slice. `time` is in 90 kHz units.
+pub fn frame_start(time: u64, keyframe: bool, fill: u8) -> [u8; TS_PACKET_SIZE] {
+    let mut packet = packet(VIDEO_PID, true, fill);
+    packet[3] = 0x30;
+    packet[4] = 7;
+    packet[5] = if keyframe { 0x50 } else { 0x10 };
+    packet[6..10].copy_from_slice(&((time >> 1) as u32).to_be_bytes());
+    packet[10] = ((time & 1) << 7) as u8 | 0x7e;
+    packet[11] = 0;
+
+    let mut pes = vec![0, 0, 1, 0xe0, 0, 0, 0x80, 0x80, 5];
+    pes.push(0x21 | ((time >> 29) & 0x0e) as u8);
+    pes.push((time >> 22) as u8);
+    pes.push(((time >> 14) & 0xfe) as u8 | 1);
+    pes.push((time >> 7) as u8);
+    pes.push(((time << 1) & 0xfe) as u8 | 1);
+    pes.extend_from_slice(&[0, 0, 0, 1, if keyframe { 0x65 } else { 0x41 }]);
+    packet[12..12 + pes.len()].copy_from_slice(&pes);
+    packet
+}
+
+/// A video of `frames` frames of 4 packets, 40 ms apart, with a keyframe
+/// preceded by a PAT and PMT every 5 frames, after `lead_in` video packets
+/// cut from the middle of a frame.
+pub fn timed_video(frames: usize, lead_in: usize) -> Vec<u8> {
+    let mut data = Vec::new();
+    for _ in 0..lead_in {
+        data.extend_from_slice(&packet(VIDEO_PID, false, 0xee));
+    }
+    for frame in 0..frames {
+        let keyframe = frame % 5 == 0;
+        if keyframe {
+            data.extend_from_slice(&pat());
+            data.extend_from_slice(&pmt());
+        }
+        let fill = frame as u8;
+        data.extend_from_slice(&frame_start(90_000 + frame as u64 * 3600, keyframe, fill));
+        for _ in 0..3 {
+            data.extend_from_slice(&packet(VIDEO_PID, false, fill));
+        }
+    }
+    data
+}
+
+/// A path in the temporary directory, unique to each call.
+fn temp_path(extension: &str) -> PathBuf {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    std::env::temp_dir().join(format!(
+        "mtf-test-{}-{}{extension}",
+        std::process::id(),
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    ))
+}
+
+/// A file in the temporary directory, removed when dropped.
+pub struct TempFile(pub PathBuf);
+
+impl TempFile {
+    pub fn new(data: &[u8]) -> Self {
+        let path = temp_path(".ts");
+        fs::write(&path, data).unwrap();
+        Self(path)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}
+
+/// An empty directory in the temporary directory, removed with its content
+/// when dropped.
+pub struct TempDir(pub PathBuf);
+
+impl TempDir {
+    pub fn new() -> Self {
+        let path = temp_path("");
+        fs::create_dir(&path).unwrap();
+        Self(path)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
@@ -0,0 +1,195 @@
+//! PCR and PTS clocks.
+
+use crate::{
+    header::{payload, pcr, MpegtsHeader},
+    pes::pts,
+};
+
+/// Frequency of the PCR.
+pub const PCR_HZ: u64 = 27_000_000;
+
+/// Frequency of PTS and DTS.
+pub const PTS_HZ: u64 = 90_000;
+
+/// PTS values wrap around after 2^33 periods.
+pub const PTS_WRAP: u64 = 1 << 33;
+
+/// PCR values wrap around after 2^33 periods of the 90 kHz base.
+const PCR_WRAP: u64 = PTS_WRAP * 300;
+
+/// Larger jumps between two clock values are discontinuities, for example
+/// at a splice, and do not count as elapsed time.
+const MAX_JUMP: u64 = 10 * PCR_HZ;
+
+/// Time elapsed since the start of a stream, from the PCR of the first PID
+/// carrying one, or the PTS of the first PID with PES timestamps while no
+/// PCR was seen.
+#[derive(Default)]
+pub struct Clock {
+    pcr_pid: Option<u16>,
+    pts_pid: Option<u16>,
+    last: Option<u64>,
+    elapsed: u64,
+}
+
+impl Clock {
+    /// A clock whose elapsed time starts at `elapsed`, in 27 MHz units.
+    pub fn starting_at(elapsed: u64) -> Self {
+        Self {
+            elapsed,
+            ..Self::default()
+        }
+    }
+
+    /// Advances the clock with the PCR or PTS of a packet, if any.
+    pub fn push(&mut self, packet: &[u8; 188]) {
+        let Ok(header) = MpegtsHeader::new(packet) else {
+            return;
+        };
+
+        if let Some(pcr) = pcr(packet) {
+            if self.pcr_pid.is_none() {
+                // switch to the PCR for good
+                self.pcr_pid = Some(header.pid);
+                self.last = None;
+            }
+            if self.pcr_pid == Some(header.pid) {
+                self.advance(pcr);
+            }
+        } else if self.pcr_pid.is_none() && header.is_start {
+            if let Some(pts) = payload(packet).and_then(pts) {
+                if *self.pts_pid.get_or_insert(header.pid) == header.pid {
+                    self.advance(pts * 300);
+                }
+            }
+        }
+    }
+
+    /// Whether a PCR or PTS was found yet.
+    pub fn is_running(&self) -> bool {
+        self.last.is_some()
+    }
+
+    /// Elapsed time in 27 MHz units.
+    pub fn elapsed(&self) -> u64 {
+        self.elapsed
+    }
+
+    fn advance(&mut self, time: u64) {
+        let Some(last) = self.last else {
+            self.last = Some(time);
+            return;
+        };
+
+        let delta = (time + PCR_WRAP - last) % PCR_WRAP;
+        if delta == 0 {
+            return;
+        }
+        if delta <= MAX_JUMP {
+            self.elapsed += delta;
+            self.last = Some(time);
+        } else if PCR_WRAP - delta > MAX_JUMP {
+            // discontinuity
+            self.last = Some(time);
+        }
+        // otherwise a PTS slightly in the past because of frame reordering
+    }
+}
+
+/// Lowest and highest PTS of a stream, compared relative to the first one
+/// modulo 2^33 so that a range across the wrap of the PTS ends with a lower
+/// value than it starts with.
+#[derive(Debug, Clone, Copy)]
+pub struct PtsRange {
+    first: u64,
+    low: i64,
+    high: i64,
+}
+
+impl PtsRange {
+    /// A range holding only `pts`.
+    pub fn new(pts: u64) -> Self {
+        Self {
+            first: pts,
+            low: 0,
+            high: 0,
+        }
+    }
+
+    /// Widens the range to hold `pts`.
+    pub fn push(&mut self, pts: u64) {
+        let delta = (pts + PTS_WRAP - self.first) % PTS_WRAP;
+        let delta = if delta < PTS_WRAP / 2 {
+            delta as i64
+        } else {
+            delta as i64 - PTS_WRAP as i64
+        };
+        self.low = self.low.min(delta);
+        self.high = self.high.max(delta);
+    }
+
+    /// Lowest PTS.
+    pub fn start(&self) -> u64 {
+        (self.first as i64 + self.low).rem_euclid(PTS_WRAP as i64) as u64
+    }
+
+    /// Highest PTS.
+    pub fn end(&self) -> u64 {
+        (self.first as i64 + self.high).rem_euclid(PTS_WRAP as i64) as u64
+    }
+}
+
+/// Formats seconds as `HH:MM:SS.mmm`.
+pub fn format_timecode(seconds: f64) -> String {
+    let millis = (seconds.max(0.0) * 1000.0).round() as u64;
+    format!(
+        "{:02}:{:02}:{:02}.{:03}",
+        millis / 3_600_000,
+        millis / 60_000 % 60,
+        millis / 1000 % 60,
+        millis % 1000
+    )
+}
+
+/// Parses `[[HH:]MM:]SS[.fff]` into seconds.
+pub fn parse_timecode(s: &str) -> anyhow::Result<f64> {
+    let mut seconds = 0.0;
+    let parts: Vec<&str> = s.split(':').collect();
+    if parts.len() > 3 {
+        anyhow::bail!("invalid timecode {s:?}, expected [[HH:]MM:]SS[.fff]");
+    }
+    for (index, part) in parts.iter().enumerate() {
+        let value: f64 = part
+            .parse()
+            .map_err(|_| anyhow::anyhow!("invalid timecode {s:?}, expected [[HH:]MM:]SS[.fff]"))?;
+        let is_last = index + 1 == parts.len();
+        if !value.is_finite() || value < 0.0 || (!is_last && value.fract() != 0.0) {
+            anyhow::bail!("invalid timecode {s:?}, expected [[HH:]MM:]SS[.fff]");
+        }
+        seconds = seconds * 60.0 + value;
+    }
+    Ok(seconds)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pts_range_of_reordered_frames() {
+        let mut range = PtsRange::new(9000);
+        for pts in [3000, 6000, 18000, 12000] {
+            range.push(pts);
+        }
+        assert_eq!((range.start(), range.end()), (3000, 18000));
+    }
+
+    #[test]
+    fn pts_range_across_the_wrap() {
+        let mut range = PtsRange::new(PTS_WRAP - 3000);
+        for pts in [PTS_WRAP - 6000, 0, 3000] {
+            range.push(pts);
+        }
+        assert_eq!((range.start(), range.end()), (PTS_WRAP - 6000, 3000));
+    }
+}